@@ -0,0 +1,122 @@
+//!
+//! This module keeps track of the .if, .ifdef and .ifndef blocks
+//! so that the lines of disabled branches are skipped.
+//!
+
+use assembler::Interm;
+use expr;
+
+#[derive(Debug)]
+pub struct Condition {
+    /// Whether the lines of the current branch are assembled
+    active: bool,
+    /// Whether a branch was taken already. Blocks inside a
+    /// disabled branch count as taken so none of their branches
+    /// is assembled.
+    taken: bool,
+    /// Whether .else was seen
+    otherwise: bool,
+    /// Location and text of the opening directive
+    location: String,
+    line: String,
+}
+
+///
+/// Checks whether a directive opens, continues or closes a
+/// block. These are followed even inside disabled branches.
+///
+pub fn is_conditional(directive: &str) -> bool {
+    matches!(directive, ".if" | ".ifdef" | ".ifndef" | ".elif" | ".else" | ".endif")
+}
+
+///
+/// Returns whether the current line should be assembled
+///
+pub fn active(interm: &Interm) -> bool {
+    interm.conditions.last().map(|c| c.active).unwrap_or(true)
+}
+
+///
+/// Opens a block with .if, .ifdef or .ifndef
+///
+pub fn begin(directive: &str, text: &str, line: &str, interm: &mut Interm) -> Result<(), String> {
+    let value = if active(interm) {
+        Some(test(directive, text, interm)?)
+    } else {
+        None
+    };
+
+    interm.conditions.push(Condition {
+        active: value == Some(true),
+        taken: value != Some(false),
+        otherwise: false,
+        location: interm.location(),
+        line: line.to_string(),
+    });
+
+    Ok(())
+}
+
+pub fn elif(text: &str, interm: &mut Interm) -> Result<(), String> {
+    let taken = match interm.conditions.last() {
+        Some(c) if c.otherwise => return Err(String::from(".elif after .else")),
+        Some(c) => c.taken,
+        None => return Err(String::from("no .if is open")),
+    };
+
+    let value = !taken && test(".if", text, interm)?;
+
+    if let Some(c) = interm.conditions.last_mut() {
+        c.active = value;
+        c.taken = taken || value;
+    }
+
+    Ok(())
+}
+
+pub fn otherwise(interm: &mut Interm) -> Result<(), String> {
+    match interm.conditions.last_mut() {
+        Some(ref c) if c.otherwise => Err(String::from(".else is already used")),
+        Some(c) => {
+            c.active = !c.taken;
+            c.taken = true;
+            c.otherwise = true;
+            Ok(())
+        }
+        None => Err(String::from("no .if is open")),
+    }
+}
+
+pub fn end(interm: &mut Interm) -> Result<(), String> {
+    match interm.conditions.pop() {
+        Some(_) => Ok(()),
+        None => Err(String::from("no .if is open")),
+    }
+}
+
+///
+/// Reports a block that is still open at the end of a file or
+/// macro body, which started with `depth` blocks open. Blocks
+/// must be closed where they are opened.
+///
+pub fn check_closed(interm: &Interm, depth: usize) -> Result<(), String> {
+    match interm.conditions.get(depth) {
+        Some(c) => Err(format!("Error: missing .endif\nLine {}:\n\n{}", c.location, c.line)),
+        None => Ok(()),
+    }
+}
+
+///
+/// Evaluates the condition of .if, .elif, .ifdef or .ifndef
+///
+fn test(directive: &str, text: &str, interm: &Interm) -> Result<bool, String> {
+    if text.is_empty() {
+        return Err(String::from("no condition provided"));
+    }
+
+    match directive {
+        ".ifdef" => Ok(interm.symtab.contains_key(text)),
+        ".ifndef" => Ok(!interm.symtab.contains_key(text)),
+        _ => Ok(expr::eval(text, &interm.symtab)? != 0),
+    }
+}
@@ -0,0 +1,459 @@
+//!
+//! This module describes the devices code is assembled for. A
+//! device comes either from the built-in table of common parts
+//! or from a part definition (.inc) file shipped with the AVR
+//! toolchain. Besides plain .equ and .def lines these files
+//! follow a few conventions: `#pragma partinc` marks the file as
+//! a part definition, `#pragma AVRPART` lines describe the part,
+//! and banner comments split the file into sections.
+//!
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use assembler::op::{Instruction, Kind, Operand};
+use expr;
+use util;
+
+///
+/// Version of the AVR core, which decides the instructions
+/// a device supports together with its flash size
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Core {
+    /// Classic core without MOVW or LPM Rd, Z
+    V0,
+    /// Classic core with MOVW, LPM Rd, Z and SPM
+    V0E,
+    /// Classic core for large parts, without MOVW
+    V1,
+    /// Enhanced core with the multiplier
+    V2,
+    /// Enhanced core with the multiplier for larger parts
+    V2E,
+    /// Enhanced core with a 22-bit program counter
+    V3,
+    XMega,
+    /// AVRrc reduced core with only r16 to r31
+    Reduced,
+}
+
+impl Core {
+    pub fn from_name(name: &str) -> Option<Core> {
+        match name.to_uppercase().as_str() {
+            "V0" => Some(Core::V0),
+            "V0E" => Some(Core::V0E),
+            "V1" => Some(Core::V1),
+            "V2" => Some(Core::V2),
+            "V2E" => Some(Core::V2E),
+            "V3" => Some(Core::V3),
+            "XMEGA" => Some(Core::XMega),
+            "AVR8L_0" => Some(Core::Reduced),
+            _ => None,
+        }
+    }
+}
+
+///
+/// An entry of the built-in device table
+///
+struct Part {
+    name: &'static str,
+    core: Core,
+    /// Sizes in bytes
+    flash: u32,
+    sram_start: u32,
+    sram: u32,
+    eeprom: u32,
+}
+
+macro_rules! part {
+    ($name:expr, $core:ident, $flash:expr, $sram_start:expr, $sram:expr, $eeprom:expr) => {
+        Part {
+            name: $name,
+            core: Core::$core,
+            flash: $flash,
+            sram_start: $sram_start,
+            sram: $sram,
+            eeprom: $eeprom,
+        }
+    };
+}
+
+static PARTS: &[Part] = &[
+    part!("ATtiny4",       Reduced,    512, 0x40,    32,    0),
+    part!("ATtiny5",       Reduced,    512, 0x40,    32,    0),
+    part!("ATtiny9",       Reduced,   1024, 0x40,    32,    0),
+    part!("ATtiny10",      Reduced,   1024, 0x40,    32,    0),
+    part!("ATtiny20",      Reduced,   2048, 0x40,   128,    0),
+    part!("ATtiny40",      Reduced,   4096, 0x40,   256,    0),
+    part!("AT90S8515",     V0,        8192, 0x60,   512,  512),
+    part!("ATtiny13",      V0E,       1024, 0x60,    64,   64),
+    part!("ATtiny13A",     V0E,       1024, 0x60,    64,   64),
+    part!("ATtiny2313",    V0E,       2048, 0x60,   128,  128),
+    part!("ATtiny2313A",   V0E,       2048, 0x60,   128,  128),
+    part!("ATtiny25",      V0E,       2048, 0x60,   128,  128),
+    part!("ATtiny45",      V0E,       4096, 0x60,   256,  256),
+    part!("ATtiny85",      V0E,       8192, 0x60,   512,  512),
+    part!("ATtiny24",      V0E,       2048, 0x60,   128,  128),
+    part!("ATtiny44",      V0E,       4096, 0x60,   256,  256),
+    part!("ATtiny84",      V0E,       8192, 0x60,   512,  512),
+    part!("ATtiny1634",    V0E,      16384, 0x100, 1024,  256),
+    part!("ATmega103",     V1,      131072, 0x60,  4000, 4096),
+    part!("ATmega8",       V2,        8192, 0x60,  1024,  512),
+    part!("ATmega48",      V2,        4096, 0x100,  512,  256),
+    part!("ATmega88",      V2,        8192, 0x100, 1024,  512),
+    part!("ATmega16",      V2E,      16384, 0x60,  1024,  512),
+    part!("ATmega32",      V2E,      32768, 0x60,  2048, 1024),
+    part!("ATmega168",     V2E,      16384, 0x100, 1024,  512),
+    part!("ATmega328",     V2E,      32768, 0x100, 2048, 1024),
+    part!("ATmega328P",    V2E,      32768, 0x100, 2048, 1024),
+    part!("ATmega32U4",    V2E,      32768, 0x100, 2560, 1024),
+    part!("ATmega644P",    V2E,      65536, 0x100, 4096, 2048),
+    part!("ATmega1284P",   V2E,     131072, 0x100, 16384, 4096),
+    part!("ATmega128",     V2E,     131072, 0x100, 4096, 4096),
+    part!("ATmega1280",    V3,      131072, 0x200, 8192, 4096),
+    part!("ATmega2560",    V3,      262144, 0x200, 8192, 4096),
+    part!("ATxmega32A4",   XMega,    36864, 0x2000, 4096, 1024),
+    part!("ATxmega128A1",  XMega,   139264, 0x2000, 8192, 2048),
+    part!("ATxmega256A3",  XMega,   270336, 0x2000, 16384, 4096),
+];
+
+///
+/// Looks up a device in the built-in table, ignoring case
+///
+pub fn find(name: &str) -> Option<Device> {
+    PARTS.iter().find(|p| p.name.eq_ignore_ascii_case(name)).map(|p| Device {
+        name: p.name.to_string(),
+        core: Some(p.core),
+        flash_size: p.flash,
+        sram_start: p.sram_start,
+        sram_size: p.sram,
+        eeprom_size: p.eeprom,
+        ..Device::default()
+    })
+}
+
+///
+/// An I/O register. Memory mapped registers can only be reached
+/// with data memory instructions such as lds and sts.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Register {
+    pub name: String,
+    pub addr: u32,
+    pub memory_mapped: bool,
+}
+
+///
+/// An entry of the interrupt vector table
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    pub name: String,
+    /// Word address of the vector
+    pub addr: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Device {
+    pub name: String,
+    pub core: Option<Core>,
+    /// Flash size in bytes
+    pub flash_size: u32,
+    pub sram_start: u32,
+    /// SRAM size in bytes
+    pub sram_size: u32,
+    /// EEPROM size in bytes
+    pub eeprom_size: u32,
+    /// I/O registers in the order they are defined
+    pub registers: Vec<Register>,
+    /// Interrupt vectors in the order they are defined
+    pub vectors: Vec<Vector>,
+    /// Size of the vector table in words
+    pub vectors_size: u32,
+}
+
+impl Device {
+    ///
+    /// Number of bits in the program counter, which is enough to
+    /// address every word of flash
+    ///
+    pub fn pc_bits(&self) -> u32 {
+        (self.flash_size / 2).next_power_of_two().trailing_zeros()
+    }
+
+    ///
+    /// Checks whether the device has an instruction form. Devices
+    /// without a known core accept every instruction.
+    ///
+    pub fn supports(&self, ins: &Instruction) -> bool {
+        use self::Core::*;
+
+        let core = match self.core {
+            Some(core) => core,
+            None => return true,
+        };
+
+        if core == Reduced && ins.operands.iter().any(|k| matches!(*k, Kind::Pq(_))) {
+            return false;
+        }
+
+        match ins.name {
+            "jmp" | "call" => self.flash_size > 8 * 1024,
+            "elpm" => self.flash_size > 64 * 1024,
+            "eijmp" | "eicall" => self.flash_size > 128 * 1024,
+            "mul" | "muls" | "mulsu" | "fmul" | "fmuls" | "fmulsu" => matches!(core, V2 | V2E | V3 | XMega),
+            "movw" | "spm" => !matches!(core, V0 | V1 | Reduced),
+            "lpm" if !ins.operands.is_empty() => !matches!(core, V0 | V1 | Reduced),
+            "lpm" | "adiw" | "sbiw" => core != Reduced,
+            // The reduced core only has the one word lds and sts
+            "lds" | "sts" => (core == Reduced) == (ins.words() == 1),
+            "espm" | "xch" | "las" | "lac" | "lat" => core == XMega,
+            _ => true,
+        }
+    }
+
+    ///
+    /// Checks that the device has the instruction, the registers
+    /// it uses and the flash address it jumps to
+    ///
+    pub fn check(&self, ins: &Instruction, operands: &[Operand]) -> Result<(), String> {
+        if !self.supports(ins) {
+            return Err(format!("{} is not supported on {}", ins.signature(), self.name));
+        }
+
+        for (kind, op) in ins.operands.iter().zip(operands) {
+            if let (&Kind::K22, &Operand::Value(addr)) = (kind, op) {
+                // Negative addresses are left to the range check
+                if addr > 0 && addr >> self.pc_bits() != 0 {
+                    return Err(format!("Address 0x{:x} is outside the flash of {}", addr, self.name));
+                }
+            }
+        }
+
+        if self.core == Some(Core::Reduced) {
+            for op in operands {
+                if let Operand::Reg(n) = *op {
+                    if n < 16 {
+                        return Err(format!("r{} is not available on {}", n, self.name));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Reads a part definition file. Returns `None` when the file is
+/// not marked with `#pragma partinc`. Lines that cannot be read
+/// are skipped since the assembler reports them when it assembles
+/// the file.
+///
+pub fn parse(file: &str) -> Option<Device> {
+    let mut device = Device::default();
+    let mut symtab = HashMap::new();
+    let mut partinc = false;
+    let mut section = String::new();
+    let mut ramend = None;
+
+    for line in file.lines() {
+        let code = util::strip_comment(line);
+        let comment = &line[code.len()..];
+        let words = util::split_string(code);
+
+        // Banner comments such as "; ***** I/O REGISTER DEFINITIONS ****"
+        // start a new section
+        if words.is_empty() {
+            if comment.starts_with("; *****") {
+                section = comment.trim_matches(|c| c == ';' || c == '*' || c == ' ').to_string();
+            }
+
+            continue;
+        }
+
+        match (words[0].to_lowercase().as_str(), words.len()) {
+            ("#pragma", 2..=usize::MAX) if words[1] == "partinc" => partinc = true,
+            ("#pragma", 4..=usize::MAX) if words[1] == "AVRPART" => pragma(&words[2..], &mut device),
+            (".device", 2) => device.name = words[1].to_string(),
+            (".equ", _) => {
+                let text = code.trim_start()[words[0].len()..].trim();
+                let eq = match text.find('=') {
+                    Some(i) => i,
+                    None => continue,
+                };
+
+                let name = text[..eq].trim();
+                let value = match expr::eval(&text[eq + 1..], &symtab) {
+                    Ok(n) => n,
+                    Err(_) => continue,
+                };
+
+                symtab.insert(name.to_string(), value);
+                let value = value as u32;
+
+                match name {
+                    "FLASHEND" => device.flash_size = (value + 1) * 2,
+                    "SRAM_START" => device.sram_start = value,
+                    "SRAM_SIZE" => device.sram_size = value,
+                    "RAMEND" => ramend = Some(value),
+                    "E2END" => device.eeprom_size = value + 1,
+                    "INT_VECTORS_SIZE" => device.vectors_size = value,
+                    _ if section.starts_with("I/O REGISTER") => device.registers.push(Register {
+                        name: name.to_string(),
+                        addr: value,
+                        memory_mapped: comment.contains("MEMORY MAPPED"),
+                    }),
+                    _ if section.starts_with("INTERRUPT VECTORS") && name.ends_with("addr") => {
+                        device.vectors.push(Vector {
+                            name: name.to_string(),
+                            addr: value,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if device.sram_size == 0 {
+        if let Some(end) = ramend {
+            device.sram_size = (end + 1).saturating_sub(device.sram_start);
+        }
+    }
+
+    if partinc {
+        Some(device)
+    } else {
+        None
+    }
+}
+
+///
+/// Reads a `#pragma AVRPART` line, given the words after AVRPART
+///
+fn pragma(words: &[&str], device: &mut Device) {
+    let number = |word: &str| util::num_from_str(word.to_string()).ok().and_then(|n| u32::try_from(n).ok());
+
+    match words {
+        ["ADMIN", "PART_NAME", name] => device.name = name.to_string(),
+        ["CORE", "CORE_VERSION", core] => device.core = Core::from_name(core),
+        ["MEMORY", "PROG_FLASH", size] => device.flash_size = number(size).unwrap_or(device.flash_size),
+        ["MEMORY", "EEPROM", size] => device.eeprom_size = number(size).unwrap_or(device.eeprom_size),
+        ["MEMORY", "INT_SRAM", "SIZE", size] => device.sram_size = number(size).unwrap_or(device.sram_size),
+        ["MEMORY", "INT_SRAM", "START_ADDR", addr] => {
+            device.sram_start = number(addr).unwrap_or(device.sram_start)
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assembler::op;
+
+    #[test]
+    fn test_parse() {
+        let file = "#pragma partinc 0\n\
+                    ; ***** SPECIFY DEVICE ***************************\n\
+                    .device ATtiny13A\n\
+                    #pragma AVRPART CORE CORE_VERSION V0E\n\
+                    ; ***** I/O REGISTER DEFINITIONS *****************\n\
+                    .equ\tSREG\t= 0x3f\n\
+                    .equ\tCLKPR\t= 0x66\t; MEMORY MAPPED\n\
+                    ; ***** BIT DEFINITIONS **************************\n\
+                    .equ\tPB0\t= 0\n\
+                    ; ***** DATA MEMORY DECLARATIONS *****************\n\
+                    .equ\tFLASHEND\t= 0x01ff\t; Note: Word address\n\
+                    .equ\tSRAM_START\t= 0x0060\n\
+                    .equ\tRAMEND\t= 0x009f\n\
+                    .equ\tE2END\t= 0x003f\n\
+                    ; ***** INTERRUPT VECTORS ************************\n\
+                    .equ\tINT0addr\t= 0x0001\t; External Interrupt 0\n\
+                    .equ\tPCI0addr\t= INT0addr + 1\n\
+                    .equ\tINT_VECTORS_SIZE\t= 10";
+
+        let device = parse(file).unwrap();
+        assert_eq!(device.name, "ATtiny13A");
+        assert_eq!(device.core, Some(Core::V0E));
+        assert_eq!(device.flash_size, 1024);
+        assert_eq!(device.sram_start, 0x60);
+        assert_eq!(device.sram_size, 64);
+        assert_eq!(device.eeprom_size, 64);
+        assert_eq!(device.registers, vec![
+            Register { name: String::from("SREG"), addr: 0x3f, memory_mapped: false },
+            Register { name: String::from("CLKPR"), addr: 0x66, memory_mapped: true },
+        ]);
+        assert_eq!(device.vectors, vec![
+            Vector { name: String::from("INT0addr"), addr: 1 },
+            Vector { name: String::from("PCI0addr"), addr: 2 },
+        ]);
+        assert_eq!(device.vectors_size, 10);
+
+        assert_eq!(parse(".equ A = 1\n.device ATmega8"), None);
+
+        let pragmas = "#pragma partinc 0\n\
+                       #pragma AVRPART ADMIN PART_NAME ATmega328P\n\
+                       #pragma AVRPART MEMORY PROG_FLASH 32768\n\
+                       #pragma AVRPART MEMORY EEPROM 1024\n\
+                       #pragma AVRPART MEMORY INT_SRAM SIZE 2048\n\
+                       #pragma AVRPART MEMORY INT_SRAM START_ADDR 0x100";
+        let device = parse(pragmas).unwrap();
+        assert_eq!(device.name, "ATmega328P");
+        assert_eq!(device.flash_size, 32768);
+        assert_eq!(device.eeprom_size, 1024);
+        assert_eq!(device.sram_size, 2048);
+        assert_eq!(device.sram_start, 0x100);
+    }
+
+    #[test]
+    fn test_find() {
+        let device = find("atmega328p").unwrap();
+        assert_eq!(device.name, "ATmega328P");
+        assert_eq!(device.core, Some(Core::V2E));
+        assert_eq!((device.flash_size, device.sram_start, device.sram_size, device.eeprom_size),
+                   (32768, 0x100, 2048, 1024));
+        assert_eq!(device.pc_bits(), 14);
+        assert_eq!(find("ATmega2560").unwrap().pc_bits(), 17);
+        assert_eq!(find("ATtiny10").unwrap().pc_bits(), 9);
+        assert_eq!(find("ATmega9000"), None);
+    }
+
+    #[test]
+    fn test_supports() {
+        let ins = |name: &str, operands: usize| {
+            op::INSTRUCTIONS.iter().find(|i| i.name == name && i.operands.len() == operands).unwrap()
+        };
+        let supports = |device: &str, name: &str, operands: usize| find(device).unwrap().supports(ins(name, operands));
+
+        assert!(supports("ATmega328P", "mul", 2));
+        assert!(!supports("ATtiny13", "mul", 2));
+        assert!(supports("ATtiny13", "movw", 2));
+        assert!(!supports("AT90S8515", "movw", 2));
+        assert!(supports("AT90S8515", "lpm", 0));
+        assert!(!supports("AT90S8515", "lpm", 2));
+        assert!(!supports("ATmega8", "jmp", 1));
+        assert!(supports("ATmega16", "jmp", 1));
+        assert!(!supports("ATmega328P", "elpm", 0));
+        assert!(supports("ATmega128", "elpm", 0));
+        assert!(!supports("ATmega1280", "eijmp", 0));
+        assert!(supports("ATmega2560", "eicall", 0));
+        assert!(!supports("ATmega2560", "xch", 2));
+        assert!(supports("ATxmega128A1", "xch", 2));
+        assert!(!supports("ATtiny10", "adiw", 2));
+        assert!(!supports("ATtiny10", "ldd", 2));
+        assert!(supports("ATtiny10", "ld", 2));
+
+        let short = |name: &str| op::INSTRUCTIONS.iter().find(|i| i.name == name && i.words() == 1).unwrap();
+        assert!(!supports("ATtiny10", "lds", 2));
+        assert!(find("ATtiny10").unwrap().supports(short("lds")));
+        assert!(find("ATtiny10").unwrap().supports(short("sts")));
+        assert!(supports("ATmega328P", "sts", 2));
+        assert!(!find("ATmega328P").unwrap().supports(short("sts")));
+        assert!(Device::default().supports(ins("xch", 2)));
+    }
+}
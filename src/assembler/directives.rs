@@ -3,7 +3,13 @@
 //! such as .cseg, .dseg, .INCLUDE, etc..
 //!
 
-use assembler::Interm;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use assembler::{conditions, device, macros, op};
+use assembler::{Interm, Line, Segment, Statement};
+use expr;
 use util;
 
 ///
@@ -11,33 +17,494 @@ use util;
 /// the interm accordingly.
 ///
 pub fn handle(line: String, interm: &mut Interm) -> Result<(), String> {
-    let tokens = util::split_string(&line);
+    let (_, stmt) = util::split_label(util::strip_comment(&line));
+    let tokens = util::split_string(stmt);
 
-    if tokens.len() == 0 {
+    if tokens.is_empty() {
         return Ok(());
     }
 
-    match tokens[0] {
-        ".org" => {
-            if tokens.len() <= 1 {
-                return Err(format!(
-                    "Error parsing .org directive: {}\nLine {}:\n\n{}",
-                    "no argument provided", interm.linectr, line
-                ));
-            }
+    let directive = tokens[0].to_lowercase();
+    let text = stmt[tokens[0].len()..].trim();
 
-            match util::num_from_str(tokens[1].to_string()) {
-                Ok(n) => interm.locctr = n,
-                Err(e) => {
-                    return Err(format!(
-                        "Error parsing .org directive: {}\nLine {}:\n\n{}",
-                        e, interm.linectr, line
-                    ));
-                }
+    let result = match directive.as_str() {
+        ".include" => match open(text, interm) {
+            // Errors in the included file carry their own location
+            Ok((path, source)) => return include(path, &source, interm),
+            Err(e) => Err(e),
+        },
+        ".device" => select_device(text, interm),
+        ".error" => match interpolate(text, interm) {
+            // User errors read like the assembler's own
+            Ok(text) => {
+                error!(text, interm.location(), line);
             }
+            Err(e) => Err(e),
+        },
+        ".warning" => interpolate(text, interm).map(|text| interm.warning(text, &line)),
+        ".message" => interpolate(text, interm).map(|text| interm.message(text, &line)),
+        ".if" | ".ifdef" | ".ifndef" => conditions::begin(&directive, text, &line, interm),
+        ".elif" => conditions::elif(text, interm),
+        ".else" => conditions::otherwise(interm),
+        ".endif" => conditions::end(interm),
+        ".macro" => macros::define(text, interm),
+        ".endm" | ".endmacro" => Err(String::from("no macro is being defined")),
+        ".exit" => exit(text, interm),
+        ".list" | ".nolist" | ".listmac" => list(&directive, text, interm),
+        ".overlap" => overlap(true, text, interm),
+        ".nooverlap" => overlap(false, text, interm),
+        ".org" => org(text, interm),
+        ".cseg" => segment(Segment::Code, text, interm),
+        ".dseg" => segment(Segment::Data, text, interm),
+        ".eseg" => segment(Segment::Eeprom, text, interm),
+        ".equ" => equ(text, interm),
+        ".set" => set(text, interm),
+        ".def" => def(text, &line, interm),
+        ".undef" => undef(text, interm),
+        ".byte" => byte(text, &line, interm),
+        ".db" => reserve(1, &line, interm),
+        ".dw" => reserve(2, &line, interm),
+        ".dd" => reserve(4, &line, interm),
+        ".dq" => reserve(8, &line, interm),
+        _ => Ok(()),
+    };
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "Error parsing {} directive: {}\nLine {}:\n\n{}",
+            directive, e, interm.location(), line
+        )),
+    }
+}
+
+///
+/// Finds the file named by .include and reads it. The file is
+/// looked up next to the file that includes it first, then in
+/// each -I directory. A part definition file also selects the
+/// device it describes.
+///
+fn open(text: &str, interm: &mut Interm) -> Result<(PathBuf, String), String> {
+    if !text.starts_with('"') {
+        return Err(String::from("expected a quoted file name"));
+    }
+
+    let name: String = util::string_literal(text)?
+        .into_iter()
+        .filter_map(::std::char::from_u32)
+        .collect();
+    let name = Path::new(&name);
+
+    let current = match interm.includes.last() {
+        Some(path) => Some(path.as_path()),
+        None => interm.path.as_deref(),
+    };
+
+    let dir = current.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+    let path = match Some(dir)
+        .into_iter()
+        .chain(interm.include_dirs.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+    {
+        Some(path) => path,
+        None => return Err(format!("cannot find \"{}\"", name.display())),
+    };
+
+    let canonical = path.canonicalize().map_err(|e| e.to_string())?;
+    let mut open = interm.path.iter().chain(interm.includes.iter().map(|p| &**p));
+
+    if open.any(|p| p.canonicalize().ok().as_ref() == Some(&canonical)) {
+        return Err(format!("recursive inclusion of \"{}\"", path.display()));
+    }
+
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => return Err(format!("failed to read \"{}\": {}", path.display(), e)),
+    };
+
+    if let Some(device) = device::parse(&source) {
+        check_device(&device.name, interm)?;
+        interm.set_device(device);
+    }
+
+    Ok((path, source))
+}
+
+///
+/// Assembles an included file in place of the directive
+///
+fn include(path: PathBuf, source: &str, interm: &mut Interm) -> Result<(), String> {
+    let linectr = interm.linectr;
+
+    interm.linectr = 0;
+    interm.includes.push(Rc::new(path));
+
+    let result = super::process(source, interm);
+
+    interm.includes.pop();
+    interm.linectr = linectr;
+    result
+}
+
+///
+/// Joins the strings and expression values given to .error,
+/// .warning and .message. Expressions are shown in decimal.
+///
+fn interpolate(text: &str, interm: &Interm) -> Result<String, String> {
+    let mut ret = String::new();
+
+    for item in util::split_operands(text) {
+        if item.starts_with('"') {
+            ret.extend(util::string_literal(item)?.into_iter().filter_map(::std::char::from_u32));
+        } else {
+            ret.push_str(&expr::eval(item, &interm.symtab)?.to_string());
+        }
+    }
+
+    if ret.is_empty() {
+        return Err(String::from("no text provided"));
+    }
+
+    Ok(ret)
+}
+
+///
+/// Selects the device from the built-in table
+///
+fn select_device(text: &str, interm: &mut Interm) -> Result<(), String> {
+    if text.is_empty() {
+        return Err(String::from("no device provided"));
+    }
+
+    // Part definition files name the device they describe,
+    // which is already set
+    if !check_device(text, interm)? {
+        return Ok(());
+    }
+
+    match device::find(text) {
+        Some(device) => {
+            interm.set_device(device);
+            Ok(())
+        }
+        None => Err(format!("unknown device \"{}\"", text)),
+    }
+}
+
+///
+/// Checks that a device can be selected. Returns false when
+/// the device is already selected.
+///
+fn check_device(name: &str, interm: &Interm) -> Result<bool, String> {
+    match interm.device {
+        Some(ref current) if current.name.eq_ignore_ascii_case(name) => Ok(false),
+        Some(ref current) => Err(format!("device is already set to {}", current.name)),
+        None => Ok(true),
+    }
+}
+
+///
+/// Stops processing the current file, if the optional
+/// condition holds
+///
+fn exit(text: &str, interm: &mut Interm) -> Result<(), String> {
+    interm.exit = text.is_empty() || expr::eval(text, &interm.symtab)? != 0;
+    Ok(())
+}
+
+fn org(text: &str, interm: &mut Interm) -> Result<(), String> {
+    if text.is_empty() {
+        return Err(String::from("no argument provided"));
+    }
+
+    let addr = expr::eval(text, &interm.symtab)?;
+
+    if addr < 0 {
+        return Err(format!("negative address ({})", addr));
+    }
+
+    if addr >= i64::from(interm.segment_end()) {
+        return Err(format!("address 0x{:x} is out of range, {}", addr, interm.segment_limit()));
+    }
+
+    interm.locctr = addr as u32;
+    Ok(())
+}
+
+///
+/// Turns the listing of source lines or of macro expansions
+/// on or off
+///
+fn list(directive: &str, text: &str, interm: &mut Interm) -> Result<(), String> {
+    if !text.is_empty() {
+        return Err(format!("unexpected argument \"{}\"", text));
+    }
+
+    if let Some(ref mut listing) = interm.listing {
+        match directive {
+            ".list" => listing.set_enabled(true),
+            ".nolist" => listing.set_enabled(false),
+            _ => listing.set_macros(true),
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Allows or forbids the following statements to reuse
+/// addresses
+///
+fn overlap(allowed: bool, text: &str, interm: &mut Interm) -> Result<(), String> {
+    if !text.is_empty() {
+        return Err(format!("unexpected argument \"{}\"", text));
+    }
+
+    interm.overlap = allowed;
+    Ok(())
+}
+
+fn segment(segment: Segment, text: &str, interm: &mut Interm) -> Result<(), String> {
+    if !text.is_empty() {
+        return Err(format!("unexpected argument \"{}\"", text));
+    }
+
+    interm.switch(segment);
+    Ok(())
+}
+
+///
+/// Reserves bytes of SRAM for a variable in the data segment
+///
+fn byte(text: &str, line: &str, interm: &mut Interm) -> Result<(), String> {
+    if interm.segment != Segment::Data {
+        return Err(String::from("only allowed in the data segment"));
+    }
+
+    if text.is_empty() {
+        return Err(String::from("no size provided"));
+    }
+
+    let size = expr::eval(text, &interm.symtab)?;
+
+    if size < 0 {
+        return Err(format!("negative size ({})", size));
+    }
+
+    if size > i64::from(u32::MAX) {
+        return Err(format!("size too large ({})", size));
+    }
+
+    let end = i64::from(interm.locctr) + size;
+
+    if let Some(sram) = interm.sram_size {
+        let limit = i64::from(interm.sram_start) + i64::from(sram);
+
+        if end > limit {
+            return Err(format!(
+                "SRAM overflow by {} bytes, SRAM ends at 0x{:x}",
+                end - limit,
+                limit - 1
+            ));
+        }
+    }
+
+    let start = interm.locctr;
+    interm.occupy(size as u32, line)?;
+    interm.reserved.push((start, interm.locctr));
+    Ok(())
+}
+
+///
+/// Splits the `NAME = expr` argument of .equ and .set and
+/// evaluates the expression
+///
+fn assignment(text: &str, interm: &Interm) -> Result<(String, i64), String> {
+    let eq = match text.find('=') {
+        Some(i) => i,
+        None => return Err(String::from("expected NAME = value")),
+    };
+
+    let name = text[..eq].trim();
+
+    if !util::is_name(name) {
+        return Err(format!("invalid symbol name \"{}\"", name));
+    }
+
+    let value = expr::eval(&text[eq + 1..], &interm.symtab)?;
+    Ok((name.to_string(), value))
+}
+
+///
+/// Defines a constant. Constants cannot be redefined
+/// unless they are removed with .undef first.
+///
+fn equ(text: &str, interm: &mut Interm) -> Result<(), String> {
+    let (name, value) = assignment(text, interm)?;
+
+    if interm.symtab.contains_key(&name) {
+        return Err(format!("redefinition of symbol \"{}\"", name));
+    }
+
+    interm.define(name, Some(value));
+    Ok(())
+}
+
+///
+/// Defines a variable that can be reassigned with .set
+/// any number of times
+///
+fn set(text: &str, interm: &mut Interm) -> Result<(), String> {
+    let (name, value) = assignment(text, interm)?;
+
+    if interm.symtab.contains_key(&name) && !interm.variables.contains(&name) {
+        return Err(format!("\"{}\" is a constant and cannot be changed with .set", name));
+    }
+
+    interm.variables.insert(name.clone());
+    interm.define(name, Some(value));
+    Ok(())
+}
+
+///
+/// Defines a register alias. Redefining an alias is allowed
+/// but produces a warning.
+///
+fn def(text: &str, line: &str, interm: &mut Interm) -> Result<(), String> {
+    let eq = match text.find('=') {
+        Some(i) => i,
+        None => return Err(String::from("expected NAME = register")),
+    };
+
+    let name = text[..eq].trim();
+    let reg = text[eq + 1..].trim();
+
+    if !util::is_name(name) {
+        return Err(format!("invalid alias name \"{}\"", name));
+    }
+
+    let n = match op::get_register(reg, interm) {
+        Some(n) => n?,
+        None => return Err(format!("\"{}\" is not a register", reg)),
+    };
+
+    // Part definition files define the pointer halves again
+    match interm.aliases.get(&name.to_lowercase()) {
+        Some(&old) if old != n => {
+            interm.warning(format!("redefinition of register alias \"{}\" (was r{})", name, old), line);
         }
         _ => {}
     }
 
+    interm.alias(name, Some(n));
+    Ok(())
+}
+
+fn undef(text: &str, interm: &mut Interm) -> Result<(), String> {
+    if text.is_empty() {
+        return Err(String::from("no symbol provided"));
+    }
+
+    if interm.aliases.contains_key(&text.to_lowercase()) {
+        interm.alias(text, None);
+        return Ok(());
+    }
+
+    if !interm.symtab.contains_key(text) {
+        return Err(format!("undefined symbol \"{}\"", text));
+    }
+
+    interm.variables.remove(text);
+    interm.define(text.to_string(), None);
+    Ok(())
+}
+
+///
+/// Counts the bytes of a data directive and makes room for them
+/// at the current address. The values are evaluated in the
+/// second pass so they can refer to labels further down.
+///
+fn reserve(size: usize, line: &str, interm: &mut Interm) -> Result<(), String> {
+    if interm.segment == Segment::Data {
+        return Err(String::from("data cannot be placed in the data segment, use .byte"));
+    }
+
+    let (_, _, items) = util::split_statement(line);
+
+    if items.is_empty() {
+        return Err(String::from("no values provided"));
+    }
+
+    let mut bytes = 0;
+
+    for item in items {
+        if !item.starts_with('"') {
+            bytes += size;
+        } else if size == 1 {
+            bytes += util::string_literal(item)?.len();
+        } else {
+            return Err(String::from("strings are only allowed in .db"));
+        }
+    }
+
+    // Code is word addressed, so odd data gets a pad byte
+    if interm.segment == Segment::Code && bytes % 2 == 1 {
+        interm.warning(String::from("odd number of bytes in .db, padded with a zero byte"), line);
+        bytes += 1;
+    }
+
+    let stmt = interm.line(Statement::Data(size), line);
+    let addresses = match interm.segment {
+        Segment::Code => bytes as u32 / 2,
+        _ => bytes as u32,
+    };
+
+    interm.occupy(addresses, line)?;
+    interm.lines.push(stmt);
     Ok(())
 }
+
+///
+/// Evaluates the values of a data directive into little-endian
+/// bytes, `size` bytes per value. Data in the code segment is
+/// padded to a whole word.
+///
+pub fn data(line: &Line, size: usize, interm: &Interm) -> Result<op::ObjectCode, String> {
+    let (_, _, items) = util::split_statement(&line.text);
+    let mut bytes = Vec::new();
+
+    for item in items {
+        if item.starts_with('"') {
+            for c in util::string_literal(item)? {
+                if c > 0xff {
+                    return Err(format!("Character out of range ({}), must fit in 8 bits", c));
+                }
+
+                bytes.push(c as u8);
+            }
+
+            continue;
+        }
+
+        // Only .dq holds literals above i64::MAX, which expressions
+        // do not accept
+        let value = match util::num_from_str(item.to_string()) {
+            Ok(n) if size == 8 => n as i64,
+            _ => expr::eval(item, &interm.symtab)?,
+        };
+        let bits = size * 8;
+
+        if bits < 64 && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+            return Err(format!("Value out of range ({}), must fit in {} bits", value, bits));
+        }
+
+        bytes.extend_from_slice(&(value as u64).to_le_bytes()[..size]);
+    }
+
+    if line.seg == Segment::Code && bytes.len() % 2 == 1 {
+        bytes.push(0);
+    }
+
+    Ok(op::ObjectCode::Data(bytes))
+}
@@ -0,0 +1,340 @@
+//!
+//! This module records the source lines for the listing file
+//! in the first pass and writes the listing once the second
+//! pass has filled in the opcodes.
+//!
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use assembler::op::ObjectCode;
+use assembler::{Interm, Segment};
+use util;
+
+/// Width of the opcode column
+const CODE_WIDTH: usize = 19;
+
+#[derive(Debug)]
+pub struct Listing {
+    /// Whether source lines are listed, turned off by .nolist
+    enabled: bool,
+    /// Whether the lines of macro expansions are listed, turned
+    /// on by .listmac
+    macros: bool,
+    entries: Vec<Entry>,
+    /// Where each symbol was defined first
+    definitions: HashMap<String, String>,
+    /// Names used by each statement, with its location
+    references: Vec<(String, String)>,
+}
+
+///
+/// A listed line. It owns the lines of the intermediate code
+/// from `first` up to `last`, or up to the next entry if that
+/// comes first.
+///
+#[derive(Debug)]
+struct Entry {
+    num: u32,
+    file: Option<Rc<PathBuf>>,
+    /// Whether the line comes from a macro expansion
+    expanded: bool,
+    text: String,
+    first: usize,
+    last: usize,
+}
+
+impl Listing {
+    pub fn new() -> Listing {
+        Listing {
+            enabled: true,
+            macros: false,
+            entries: Vec::new(),
+            definitions: HashMap::new(),
+            references: Vec::new(),
+        }
+    }
+
+    ///
+    /// Forgets everything recorded, for a new first pass
+    ///
+    pub fn reset(&mut self) {
+        *self = Listing::new();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn set_macros(&mut self, macros: bool) {
+        self.macros = macros;
+    }
+}
+
+///
+/// Adds a line to the listing if listing is on. Returns the
+/// index of the entry, to be passed to `end` once the line
+/// is assembled.
+///
+pub fn begin(text: &str, expanded: bool, interm: &mut Interm) -> Option<usize> {
+    let first = interm.lines.len();
+    let num = interm.linectr;
+    let file = interm.includes.last().cloned();
+    let listing = interm.listing.as_mut()?;
+
+    if !listing.enabled || (expanded && !listing.macros) {
+        return None;
+    }
+
+    listing.entries.push(Entry {
+        num,
+        file,
+        expanded,
+        text: text.to_string(),
+        first,
+        last: first,
+    });
+
+    Some(listing.entries.len() - 1)
+}
+
+pub fn end(entry: Option<usize>, interm: &mut Interm) {
+    let last = interm.lines.len();
+
+    if let (Some(i), Some(listing)) = (entry, interm.listing.as_mut()) {
+        listing.entries[i].last = last;
+    }
+}
+
+///
+/// Remembers where a symbol is defined for the cross-reference
+///
+pub fn define(name: &str, interm: &mut Interm) {
+    let location = interm.location();
+
+    if let Some(ref mut listing) = interm.listing {
+        listing.definitions.entry(name.to_string()).or_insert(location);
+    }
+}
+
+///
+/// Remembers the names an active statement uses for the
+/// cross-reference
+///
+pub fn reference(line: &str, interm: &mut Interm) {
+    if interm.listing.is_none() {
+        return;
+    }
+
+    let location = interm.location();
+    let (_, stmt) = util::split_label(util::strip_comment(line));
+    let operands = match stmt.find(char::is_whitespace) {
+        Some(i) => &stmt[i..],
+        None => return,
+    };
+
+    if let Some(ref mut listing) = interm.listing {
+        for name in names(operands) {
+            listing.references.push((name.to_string(), location.clone()));
+        }
+    }
+}
+
+///
+/// Finds the identifiers in an expression, skipping numbers
+/// and quoted text
+///
+fn names(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                while let Some((_, d)) = chars.next() {
+                    if d == '\\' {
+                        chars.next();
+                    } else if d == c {
+                        break;
+                    }
+                }
+            }
+            _ if c.is_ascii_alphanumeric() || c == '_' || c == '$' => {
+                let mut end = i + c.len_utf8();
+
+                while let Some(&(j, d)) = chars.peek() {
+                    if !d.is_ascii_alphanumeric() && d != '_' {
+                        break;
+                    }
+
+                    end = j + d.len_utf8();
+                    chars.next();
+                }
+
+                if c.is_ascii_alphabetic() || c == '_' {
+                    names.push(&text[i..end]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    names
+}
+
+///
+/// Writes the listing: every listed line with its address and
+/// code, followed by the symbol table and cross-reference.
+/// Call this after the second pass.
+///
+pub fn generate(interm: &Interm) -> String {
+    let mut out = String::new();
+    let listing = match interm.listing {
+        Some(ref listing) => listing,
+        None => return out,
+    };
+
+    let main = interm.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+    let _ = writeln!(out, "AVR assembler listing of {}", main);
+
+    let mut file = None;
+
+    for (i, entry) in listing.entries.iter().enumerate() {
+        if !entry.expanded && entry.file != file {
+            file = entry.file.clone();
+            let name = file.as_ref().map(|p| p.display().to_string()).unwrap_or_else(|| main.clone());
+            let _ = writeln!(out, "\n{}:\n", name);
+        }
+
+        let last = match listing.entries.get(i + 1) {
+            Some(next) => entry.last.min(next.first),
+            None => entry.last,
+        };
+        let lines = &interm.lines[entry.first..last.max(entry.first)];
+
+        let num = if entry.expanded { String::from("+") } else { entry.num.to_string() };
+        let addr = match lines.first() {
+            Some(line) => format!("{}:{:06x}", segment(line.seg), line.addr),
+            None => String::new(),
+        };
+
+        let mut rows = rows(lines.iter().filter_map(|l| l.opcode.as_ref())).into_iter();
+        let code = rows.next().unwrap_or_default();
+
+        let _ = writeln!(
+            out,
+            "{}",
+            format!("{:>5}  {:8}  {:w$}  {}", num, addr, code, entry.text, w = CODE_WIDTH).trim_end()
+        );
+
+        for row in rows {
+            let _ = writeln!(out, "{:17}{}", "", row);
+        }
+    }
+
+    symbols(listing, interm, &mut out);
+    out
+}
+
+fn segment(seg: Segment) -> char {
+    match seg {
+        Segment::Code => 'C',
+        Segment::Data => 'D',
+        Segment::Eeprom => 'E',
+    }
+}
+
+///
+/// Lays out opcodes as hex words and data as hex bytes,
+/// as many as fit in the code column per row
+///
+fn rows<'a, I: Iterator<Item = &'a ObjectCode>>(codes: I) -> Vec<String> {
+    let mut rows = Vec::new();
+    let mut row = String::new();
+
+    for code in codes {
+        let items = match *code {
+            ObjectCode::Short(w) => vec![format!("{:04x}", w)],
+            ObjectCode::Long(w) => vec![format!("{:04x}", w >> 16), format!("{:04x}", w & 0xffff)],
+            ObjectCode::Data(ref bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+
+        for item in items {
+            if !row.is_empty() && row.len() + 1 + item.len() > CODE_WIDTH {
+                rows.push(row);
+                row = String::new();
+            }
+
+            if !row.is_empty() {
+                row.push(' ');
+            }
+
+            row.push_str(&item);
+        }
+    }
+
+    if !row.is_empty() {
+        rows.push(row);
+    }
+
+    rows
+}
+
+///
+/// Writes every symbol with its value, where it is defined and
+/// the statements that use it
+///
+fn symbols(listing: &Listing, interm: &Interm, out: &mut String) {
+    let mut used: HashMap<&str, Vec<&str>> = HashMap::new();
+
+    for (name, location) in &listing.references {
+        if interm.symtab.contains_key(name) && listing.definitions.get(name) != Some(location) {
+            let locations = used.entry(name).or_default();
+
+            if locations.last() != Some(&location.as_str()) {
+                locations.push(location);
+            }
+        }
+    }
+
+    let mut names: Vec<&String> = interm.symtab.keys().collect();
+    names.sort_by_key(|name| (name.to_lowercase(), name.to_string()));
+
+    let _ = writeln!(out, "\nSymbols:\n");
+
+    for name in names {
+        let value = interm.symtab[name];
+        let value = if value < 0 { value.to_string() } else { format!("0x{:04x}", value) };
+        let _ = match listing.definitions.get(name) {
+            Some(location) => writeln!(out, "{:24} {:10} defined at {}", name, value, location),
+            None => writeln!(out, "{:24} {}", name, value),
+        };
+
+        if let Some(locations) = used.get(name.as_str()) {
+            let _ = writeln!(out, "{:35} used at {}", "", locations.join("; "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(names(" r16, LOW(count + 1)"), vec!["r16", "LOW", "count"]);
+        assert_eq!(names(" 0x1F, $FF, 0b101, _tmp"), vec!["_tmp"]);
+        assert_eq!(names(" \"a name\", 'x', '\\'', end"), vec!["end"]);
+    }
+
+    #[test]
+    fn test_rows() {
+        let codes = [ObjectCode::Short(0xe00f), ObjectCode::Long(0x940c_0010)];
+        assert_eq!(rows(codes.iter()), vec!["e00f 940c 0010"]);
+
+        let codes = [ObjectCode::Data(vec![1, 2, 3, 4, 5, 6, 7, 8])];
+        assert_eq!(rows(codes.iter()), vec!["01 02 03 04 05 06", "07 08"]);
+    }
+}
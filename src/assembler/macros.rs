@@ -0,0 +1,260 @@
+//!
+//! This module records macro definitions and expands macro calls
+//! in the first pass.
+//!
+
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use assembler::{conditions, listing, location, statement, Interm};
+use util;
+
+/// How deep macros may call other macros
+const MAX_DEPTH: usize = 16;
+
+/// Joins a local label to the number of its expansion. Names
+/// the user defines cannot contain it, so local labels never
+/// clash with them.
+const LOCAL: char = '?';
+
+#[derive(Debug)]
+pub struct Macro {
+    name: String,
+    /// Location of the .macro line
+    file: Option<Rc<PathBuf>>,
+    line: u32,
+    body: Vec<String>,
+}
+
+///
+/// A macro being expanded, with the body line being processed
+///
+#[derive(Debug)]
+pub struct Frame {
+    name: String,
+    file: Option<Rc<PathBuf>>,
+    line: u32,
+    /// Number of the expansion, given to its local labels
+    id: u32,
+}
+
+impl Frame {
+    pub fn location(&self) -> String {
+        format!("{} in macro {}", location(self.line, &self.file), self.name)
+    }
+}
+
+///
+/// Starts recording the body of a macro
+///
+pub fn define(text: &str, interm: &mut Interm) -> Result<(), String> {
+    let name = match util::split_string(text).first() {
+        Some(name) => name.to_string(),
+        None => return Err(String::from("no macro name provided")),
+    };
+
+    if interm.macros.contains_key(&name.to_lowercase()) {
+        return Err(format!("macro \"{}\" is already defined", name));
+    }
+
+    interm.recording = Some(Macro {
+        name,
+        file: interm.includes.last().cloned(),
+        line: interm.linectr,
+        body: Vec::new(),
+    });
+
+    Ok(())
+}
+
+///
+/// Adds a line to the macro being recorded. The recording
+/// ends at .endm or .endmacro.
+///
+pub fn record(line: &str, mnemonic: Option<&str>, interm: &mut Interm) -> Result<(), String> {
+    match mnemonic.map(str::to_lowercase).as_deref() {
+        Some(".endm") | Some(".endmacro") => {
+            if let Some(mac) = interm.recording.take() {
+                interm.macros.insert(mac.name.to_lowercase(), Rc::new(mac));
+            }
+        }
+        Some(".macro") => {
+            error!("macro definitions cannot be nested", interm.location(), line);
+        }
+        _ => {
+            if let Some(ref mut mac) = interm.recording {
+                mac.body.push(line.to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Reports a macro that is still being recorded at the end
+/// of the source
+///
+pub fn check_recording(interm: &Interm) -> Result<(), String> {
+    match interm.recording {
+        Some(ref mac) => Err(format!(
+            "Error: missing .endm for macro \"{}\"\nLine {}:\n\n.macro {}",
+            mac.name,
+            location(mac.line, &mac.file),
+            mac.name
+        )),
+        None => Ok(()),
+    }
+}
+
+///
+/// Assembles the body of a macro in place of the call. `@0`
+/// to `@9` are replaced by the arguments, and the labels the
+/// body defines get a name of their own in every expansion.
+///
+pub fn expand(name: &str, args: &[&str], line: &str, interm: &mut Interm) -> Result<(), String> {
+    let mac = interm.macros[name].clone();
+
+    if interm.expansions.len() >= MAX_DEPTH {
+        error!(
+            format!("macro \"{}\" nested more than {} levels deep", mac.name, MAX_DEPTH),
+            interm.location(),
+            line
+        );
+    }
+
+    let depth = interm.conditions.len();
+    let labels: Vec<&str> = mac.body.iter().filter_map(|l| util::split_label(util::strip_comment(l)).0).collect();
+
+    interm.expanded += 1;
+    let id = interm.expanded;
+
+    interm.expansions.push(Frame {
+        name: mac.name.clone(),
+        file: mac.file.clone(),
+        line: mac.line,
+        id,
+    });
+
+    for (i, text) in mac.body.iter().enumerate() {
+        if let Some(frame) = interm.expansions.last_mut() {
+            frame.line = mac.line + 1 + i as u32;
+        }
+
+        let text = match substitute(text, args, &labels, id) {
+            Ok(text) => text,
+            Err(e) => {
+                error!(e, interm.location(), text);
+            }
+        };
+
+        let entry = listing::begin(&text, true, interm);
+        statement(&text, interm)?;
+        listing::end(entry, interm);
+
+        // .exit leaves the blocks for the file to close
+        if interm.exit {
+            interm.expansions.pop();
+            return Ok(());
+        }
+    }
+
+    conditions::check_closed(interm, depth)?;
+    interm.expansions.pop();
+    Ok(())
+}
+
+///
+/// Checks whether `name` is a local label of the expansion
+/// being assembled
+///
+pub fn is_local(name: &str, interm: &Interm) -> bool {
+    match (name.rfind(LOCAL), interm.expansions.last()) {
+        (Some(i), Some(frame)) => util::is_name(&name[..i]) && name[i + 1..] == frame.id.to_string(),
+        _ => false,
+    }
+}
+
+///
+/// Replaces the parameters and local labels in one line
+/// of a macro body
+///
+fn substitute(text: &str, args: &[&str], labels: &[&str], id: u32) -> Result<String, String> {
+    let mut ret = String::new();
+    let mut chars = text.char_indices().peekable();
+    let mut quote = None;
+
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('@', _) => {
+                let n = match chars.peek().and_then(|&(_, d)| d.to_digit(10)) {
+                    Some(n) => n as usize,
+                    None => {
+                        ret.push(c);
+                        continue;
+                    }
+                };
+
+                chars.next();
+
+                match args.get(n) {
+                    Some(arg) => ret.push_str(arg),
+                    None => return Err(format!("@{} used but only {} arguments were given", n, args.len())),
+                }
+            }
+            (';', None) => {
+                ret.push_str(&text[i..]);
+                break;
+            }
+            ('"', None) | ('\'', None) => {
+                quote = Some(c);
+                ret.push(c);
+            }
+            (c, Some(q)) if c == q => {
+                quote = None;
+                ret.push(c);
+            }
+            (c, None) if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut end = i + c.len_utf8();
+
+                while let Some(&(j, d)) = chars.peek() {
+                    if !d.is_ascii_alphanumeric() && d != '_' {
+                        break;
+                    }
+
+                    end = j + d.len_utf8();
+                    chars.next();
+                }
+
+                let word = &text[i..end];
+
+                if labels.contains(&word) {
+                    ret.push_str(&format!("{}{}{}", word, LOCAL, id));
+                } else {
+                    ret.push_str(word);
+                }
+            }
+            _ => ret.push(c),
+        }
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_substitute() {
+        assert_eq!(substitute("ldi @0, LOW(@1)", &["r16", "1000"], &[], 1), Ok(String::from("ldi r16, LOW(1000)")));
+        assert_eq!(substitute("loop: dec @0", &["r17"], &["loop"], 3), Ok(String::from("loop?3: dec r17")));
+        assert_eq!(substitute("brne loop ; loop again", &[], &["loop"], 3),
+                   Ok(String::from("brne loop?3 ; loop again")));
+        assert_eq!(substitute("rjmp loops", &[], &["loop"], 3), Ok(String::from("rjmp loops")));
+        assert_eq!(substitute(".db \"loop @0\"", &["x"], &["loop"], 3), Ok(String::from(".db \"loop x\"")));
+        assert_eq!(substitute("st X, r0 ; @ is kept", &[], &[], 1), Ok(String::from("st X, r0 ; @ is kept")));
+        assert_eq!(substitute("mov @0, @2", &["r1", "r2"], &[], 1),
+                   Err(String::from("@2 used but only 2 arguments were given")));
+    }
+}
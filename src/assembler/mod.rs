@@ -2,7 +2,9 @@
 //! This module is responsible for assembling the pre-processed
 //! code
 //!
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
 
 use util;
 
@@ -15,16 +17,99 @@ macro_rules! error {
     };
 }
 
+mod conditions;
+pub mod device;
 mod directives;
+pub mod listing;
+mod macros;
 mod op;
 
+///
+/// The address spaces code and data can be placed in. Code is
+/// word addressed, SRAM and EEPROM are byte addressed.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Code,
+    Data,
+    Eeprom,
+}
+
+impl Segment {
+    pub fn name(self) -> &'static str {
+        match self {
+            Segment::Code => "code",
+            Segment::Data => "data",
+            Segment::Eeprom => "EEPROM",
+        }
+    }
+}
+
+///
+/// What a line assembles into
+///
+#[derive(Debug)]
+pub enum Statement {
+    Instruction(&'static op::Instruction),
+    /// Data from .db, .dw, .dd or .dq with the size of each value in bytes
+    Data(usize),
+}
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Line {
     num: u32,
+    /// Included file the line comes from, `None` for the main file
+    #[derivative(Debug="ignore")]
+    file: Option<Rc<PathBuf>>,
+    /// Location inside the macros the line was expanded from
+    #[derivative(Debug="ignore")]
+    expansion: Option<String>,
     addr: u32,
-    ins: op::Instruction,
-    opcode: i32,
+    seg: Segment,
+    stmt: Statement,
+    #[derivative(Debug="ignore")]
+    text: String,
+    opcode: Option<op::ObjectCode>,
+}
+
+///
+/// A change made to the symbol or register alias tables by a
+/// directive. `None` removes the name.
+///
+#[derive(Debug, Clone)]
+pub enum Change {
+    Symbol(String, Option<i64>),
+    Alias(String, Option<u32>),
+}
+
+impl Line {
+    ///
+    /// Describes where the line is for diagnostics
+    ///
+    pub fn location(&self) -> String {
+        match self.expansion {
+            Some(ref expansion) => expansion.to_string(),
+            None => location(self.num, &self.file),
+        }
+    }
+}
+
+///
+/// Addresses used by one statement, up to `end`
+///
+#[derive(Debug)]
+pub struct Usage {
+    end: u32,
+    location: String,
+    line: String,
+}
+
+fn location(num: u32, file: &Option<Rc<PathBuf>>) -> String {
+    match *file {
+        Some(ref path) => format!("{} of {}", num, path.display()),
+        None => num.to_string(),
+    }
 }
 
 #[derive(Derivative)]
@@ -32,18 +117,355 @@ pub struct Line {
 pub struct Interm {
     pub lines: Vec<Line>,
     #[derivative(Debug="ignore")]
-    pub instructions: HashMap<&'static str, op::Instruction>,
+    pub instructions: HashMap<&'static str, Vec<&'static op::Instruction>>,
     pub optab: Vec<String>,
+    /// Location counter of the active segment
     pub locctr: u32,
     pub linectr: u32,
-    pub symtab: HashMap<String, u32>,
+    pub segment: Segment,
+    /// Location counters of each segment while it is not active
+    pub counters: [u32; 3],
+    /// First SRAM address, where the data segment starts
+    pub sram_start: u32,
+    /// Size of the SRAM in bytes, if the device is known
+    pub sram_size: Option<u32>,
+    /// Device read from an included part definition file
+    pub device: Option<device::Device>,
+    pub symtab: HashMap<String, i64>,
+    /// Symbols defined with .set, which can be reassigned
+    pub variables: HashSet<String>,
+    /// Register aliases defined with .def, keyed by lowercase name
+    pub aliases: HashMap<String, u32>,
+    /// Changes made by .equ, .set, .def and .undef, keyed by the index
+    /// of the next line so the second pass can replay them in order
+    pub history: Vec<(usize, Change)>,
+    /// Warnings and messages in the order they were reported
+    pub diagnostics: Vec<String>,
+    /// Path of the main file, used to resolve .include
+    pub path: Option<PathBuf>,
+    /// Directories searched by .include when the file is not found
+    /// next to the file that includes it
+    pub include_dirs: Vec<PathBuf>,
+    /// Files being included, innermost last
+    pub includes: Vec<Rc<PathBuf>>,
+    /// Macros by lowercase name
+    pub macros: HashMap<String, Rc<macros::Macro>>,
+    /// Macro whose body is being recorded
+    pub recording: Option<macros::Macro>,
+    /// Macros being expanded, innermost last
+    pub expansions: Vec<macros::Frame>,
+    /// Number of macro expansions so far, which keeps the
+    /// labels of each expansion apart
+    pub expanded: u32,
+    /// Open .if blocks, innermost last
+    pub conditions: Vec<conditions::Condition>,
+    /// Set by .exit to stop processing the current file
+    pub exit: bool,
+    /// Addresses used so far in each segment, keyed by the first
+    /// address of every statement
+    pub used: [BTreeMap<u32, Usage>; 3],
+    /// Set by .overlap, lets the following statements reuse addresses
+    pub overlap: bool,
+    /// SRAM ranges reserved with .byte, overlapping ones included
+    pub reserved: Vec<(u32, u32)>,
+    /// Lines and symbols for the listing file, if one is written
+    #[derivative(Debug="ignore")]
+    pub listing: Option<listing::Listing>,
+    /// Instruction forms picked by the relaxation pass, keyed by
+    /// the location of the line. Indexes would shift when an
+    /// address dependent .if changes the number of lines.
+    pub relaxed: HashMap<String, &'static op::Instruction>,
 }
 
 impl Interm {
+    pub fn new() -> Interm {
+        Interm {
+            lines: Vec::new(),
+            optab: Vec::new(),
+            instructions: HashMap::new(),
+            locctr: 0,
+            linectr: 0,
+            segment: Segment::Code,
+            counters: [0; 3],
+            sram_start: 0x60,
+            sram_size: None,
+            device: None,
+            symtab: HashMap::new(),
+            variables: HashSet::new(),
+            aliases: HashMap::new(),
+            history: Vec::new(),
+            diagnostics: Vec::new(),
+            path: None,
+            include_dirs: Vec::new(),
+            includes: Vec::new(),
+            macros: HashMap::new(),
+            recording: None,
+            expansions: Vec::new(),
+            expanded: 0,
+            conditions: Vec::new(),
+            exit: false,
+            used: [BTreeMap::new(), BTreeMap::new(), BTreeMap::new()],
+            overlap: false,
+            reserved: Vec::new(),
+            listing: None,
+            relaxed: HashMap::new(),
+        }
+    }
+
+    ///
+    /// Assigns a value to a symbol, or removes it when the value
+    /// is `None`, and remembers the position of the change
+    ///
+    pub fn define(&mut self, name: String, value: Option<i64>) {
+        if value.is_some() {
+            listing::define(&name, self);
+        }
+
+        let change = Change::Symbol(name, value);
+        self.apply(&change);
+        self.history.push((self.lines.len(), change));
+    }
+
+    ///
+    /// Points a register alias at a register, or removes it when
+    /// the register is `None`, and remembers the position of the
+    /// change
+    ///
+    pub fn alias(&mut self, name: &str, reg: Option<u32>) {
+        let change = Change::Alias(name.to_lowercase(), reg);
+        self.apply(&change);
+        self.history.push((self.lines.len(), change));
+    }
+
+    fn apply(&mut self, change: &Change) {
+        match *change {
+            Change::Symbol(ref name, Some(n)) => {
+                self.symtab.insert(name.to_string(), n);
+            }
+            Change::Symbol(ref name, None) => {
+                self.symtab.remove(name);
+            }
+            Change::Alias(ref name, Some(n)) => {
+                self.aliases.insert(name.to_string(), n);
+            }
+            Change::Alias(ref name, None) => {
+                self.aliases.remove(name);
+            }
+        }
+    }
+
+    ///
+    /// Records a warning for the line being processed
+    ///
+    pub fn warning(&mut self, reason: String, line: &str) {
+        self.diagnostics.push(format!("Warning: {}\nLine {}:\n\n{}", reason, self.location(), line));
+    }
+
+    ///
+    /// Records an informational message for the line being processed
+    ///
+    pub fn message(&mut self, text: String, line: &str) {
+        self.diagnostics.push(format!("Message: {}\nLine {}:\n\n{}", text, self.location(), line));
+    }
+
+    ///
+    /// Describes the line being processed for diagnostics
+    ///
+    pub fn location(&self) -> String {
+        let mut ret = location(self.linectr, &self.includes.last().cloned());
+
+        for frame in &self.expansions {
+            ret = format!("{}, called from line {}", frame.location(), ret);
+        }
+
+        ret
+    }
+
+    ///
+    /// Creates a line at the current address
+    ///
+    fn line(&self, stmt: Statement, text: &str) -> Line {
+        Line {
+            num: self.linectr,
+            file: self.includes.last().cloned(),
+            expansion: if self.expansions.is_empty() {
+                None
+            } else {
+                Some(self.location())
+            },
+            addr: self.locctr,
+            seg: self.segment,
+            stmt,
+            text: text.to_string(),
+            opcode: None,
+        }
+    }
+
+    ///
+    /// Defines the register aliases every part has
+    ///
+    fn init_aliases(&mut self) {
+        self.aliases.clear();
+
+        for (i, name) in ["xl", "xh", "yl", "yh", "zl", "zh"].iter().enumerate() {
+            self.aliases.insert(name.to_string(), 26 + i as u32);
+        }
+    }
+
     pub fn reset_counters(&mut self) {
         self.locctr = 0;
         self.linectr = 0;
     }
+
+    ///
+    /// Starts every segment at its first address with the
+    /// code segment active
+    ///
+    fn reset_segments(&mut self) {
+        self.segment = Segment::Code;
+        self.counters = [0, self.sram_start, 0];
+    }
+
+    ///
+    /// Makes another segment active, keeping the location
+    /// counter of the one that was active
+    ///
+    pub fn switch(&mut self, segment: Segment) {
+        self.counters[self.segment as usize] = self.locctr;
+        self.segment = segment;
+        self.locctr = self.counters[segment as usize];
+    }
+
+    ///
+    /// Returns the location counter of a segment
+    ///
+    pub fn counter(&self, segment: Segment) -> u32 {
+        if segment == self.segment {
+            self.locctr
+        } else {
+            self.counters[segment as usize]
+        }
+    }
+
+    ///
+    /// Makes `device` the target of the assembly. The data segment
+    /// moves to the device's SRAM if nothing was reserved yet.
+    ///
+    pub fn set_device(&mut self, mut device: device::Device) {
+        // Part definition files do not always name the core
+        if device.core.is_none() {
+            device.core = device::find(&device.name).and_then(|d| d.core);
+        }
+
+        if self.counter(Segment::Data) == self.sram_start {
+            match self.segment {
+                Segment::Data => self.locctr = device.sram_start,
+                _ => self.counters[Segment::Data as usize] = device.sram_start,
+            }
+        }
+
+        self.sram_start = device.sram_start;
+        self.sram_size = Some(device.sram_size);
+        self.device = Some(device);
+    }
+
+    ///
+    /// Returns the address after the last one of the active
+    /// segment on the selected device. Without a device, or when
+    /// the device does not give the size, it is only limited by
+    /// the location counter.
+    ///
+    pub fn segment_end(&self) -> u32 {
+        let end = match self.device {
+            Some(ref device) => match self.segment {
+                // Code is word addressed, so flash holds half as many addresses
+                Segment::Code => device.flash_size / 2,
+                Segment::Data => device.sram_start + device.sram_size,
+                // Parts such as the ATtiny10 have no EEPROM at all
+                Segment::Eeprom => return device.eeprom_size,
+            },
+            None => 0,
+        };
+
+        if end == 0 {
+            u32::MAX
+        } else {
+            end
+        }
+    }
+
+    ///
+    /// Describes where the active segment ends, for errors
+    ///
+    pub fn segment_limit(&self) -> String {
+        match (self.segment_end(), self.device.as_ref()) {
+            (0, Some(device)) => format!("{} has no {} segment", device.name, self.segment.name()),
+            (end, _) => format!("the {} segment ends at 0x{:x}", self.segment.name(), end.saturating_sub(1)),
+        }
+    }
+
+    ///
+    /// Advances the location counter past `size` addresses used by
+    /// `line`. Reusing an address is an error unless one of the two
+    /// statements follows .overlap, whose addresses are not tracked.
+    ///
+    pub fn occupy(&mut self, size: u32, line: &str) -> Result<(), String> {
+        let start = self.locctr;
+        let end = match start.checked_add(size) {
+            Some(end) if end <= self.segment_end() => end,
+            _ => return Err(format!("address out of range, {}", self.segment_limit())),
+        };
+
+        if size > 0 && !self.overlap {
+            let location = self.location();
+            let used = &mut self.used[self.segment as usize];
+
+            if let Some((&addr, usage)) = used.range(..end).next_back() {
+                if usage.end > start {
+                    return Err(format!(
+                        "address 0x{:x} of the {} segment is already used by line {}: {}",
+                        start.max(addr),
+                        self.segment.name(),
+                        usage.location,
+                        usage.line.trim()
+                    ));
+                }
+            }
+
+            let usage = Usage {
+                end,
+                location,
+                line: line.to_string(),
+            };
+            used.insert(start, usage);
+        }
+
+        self.locctr = end;
+        Ok(())
+    }
+
+    ///
+    /// Returns the number of SRAM bytes reserved with .byte.
+    /// Bytes reserved more than once count once.
+    ///
+    pub fn ram_used(&self) -> u32 {
+        let mut ranges = self.reserved.clone();
+        ranges.sort();
+
+        let mut used = 0;
+        let mut end = 0;
+
+        for (start, stop) in ranges {
+            let start = start.max(end);
+
+            if stop > start {
+                used += stop - start;
+                end = stop;
+            }
+        }
+
+        used
+    }
 }
 
 ///
@@ -52,54 +474,256 @@ impl Interm {
 ///
 /// Note: This function will mutate the `interm` parameter.
 ///
-pub fn first_pass(file: &String, interm: &mut Interm) -> Result<(), String> {
+pub fn first_pass(file: &str, interm: &mut Interm) -> Result<(), String> {
     op::init_op_map(interm);
     interm.reset_counters();
+    interm.reset_segments();
+    interm.lines.clear();
+    interm.variables.clear();
+    interm.history.clear();
+    interm.diagnostics.clear();
+    interm.includes.clear();
+    interm.macros.clear();
+    interm.recording = None;
+    interm.expansions.clear();
+    interm.expanded = 0;
+    interm.conditions.clear();
+    interm.exit = false;
+    interm.used.iter_mut().for_each(BTreeMap::clear);
+    interm.overlap = false;
+    interm.reserved.clear();
+    interm.init_aliases();
 
-    for line in file.lines() {
-        let line = line.to_string();
-        let mut tokens = util::split_string(&line);
+    if let Some(ref mut listing) = interm.listing {
+        listing.reset();
+    }
+
+    process(file, interm)?;
+    macros::check_recording(interm)?;
 
+    // Keep the counter of the last segment for the totals
+    interm.switch(Segment::Code);
+    Ok(())
+}
+
+///
+/// Runs the first pass over the lines of one file. Included
+/// files are processed by calling this again from .include.
+///
+fn process(file: &str, interm: &mut Interm) -> Result<(), String> {
+    let conditions = interm.conditions.len();
+
+    for line in file.lines() {
         interm.linectr += 1;
-        println!("{:3} ({:4}): {}", interm.linectr, interm.locctr, line);
 
-        // Skip blank lines
-        if tokens.len() == 0 {
-            continue;
+        let entry = listing::begin(line, false, interm);
+        statement(line, interm)?;
+        listing::end(entry, interm);
+
+        // .exit ends the file, along with the blocks it opened
+        if interm.exit {
+            interm.exit = false;
+            interm.conditions.truncate(conditions);
+            break;
         }
+    }
 
-        // Handle comments and assembler directives
-        match &tokens[0][..1] {
-            ";" | "#" => continue,
-            "." => match directives::handle(line.to_string(), interm) {
-                Ok(_) => continue,
-                Err(e) => return Err(e),
-            },
-            _ => {}
+    conditions::check_closed(interm, conditions)
+}
+
+///
+/// Runs the first pass over one statement, which comes either
+/// from a file or from the expansion of a macro
+///
+fn statement(line: &str, interm: &mut Interm) -> Result<(), String> {
+    let tokens = util::split_string(util::strip_comment(line));
+
+    // Skip blank lines and comments
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    // Skip preprocessor directives
+    if tokens[0].starts_with('#') {
+        return Ok(());
+    }
+
+    let (label, mnemonic, operands) = util::split_statement(line);
+
+    if interm.recording.is_some() {
+        return macros::record(line, mnemonic, interm);
+    }
+
+    // Lines in disabled branches are neither defined nor sized,
+    // so the second pass never sees them
+    let directive = mnemonic.map(str::to_lowercase).unwrap_or_default();
+
+    if conditions::is_conditional(&directive) {
+        return directives::handle(line.to_string(), interm);
+    }
+
+    if !conditions::active(interm) {
+        return Ok(());
+    }
+
+    listing::reference(line, interm);
+
+    if let Some(symbol) = label {
+        if symbol.is_empty() {
+            error!("missing label name", interm.location(), line);
+        } else if !util::is_name(symbol) && !macros::is_local(symbol, interm) {
+            error!(format!("invalid label name \"{}\"", symbol), interm.location(), line);
+        } else if interm.symtab.contains_key(symbol) {
+            error!(
+                format!("redefinition of symbol \"{}\"", symbol),
+                interm.location(),
+                line
+            );
+        } else {
+            interm.symtab.insert(symbol.to_string(), i64::from(interm.locctr));
+            listing::define(symbol, interm);
         }
+    }
 
-        if tokens[0].ends_with(":") {
-            let symbol = &tokens[0][..tokens[0].len() - 1];
+    let mnemonic = match mnemonic {
+        Some(m) => m.to_lowercase(),
+        None => return Ok(()),
+    };
 
-            if interm.symtab.contains_key(symbol) {
-                error!(
-                    format!("redefinition of symbol \"{}\"", symbol),
-                    interm.linectr,
-                    line
-                );
-            } else {
-                interm.symtab.insert(symbol.to_string(), interm.locctr);
+    // Handle assembler directives
+    if mnemonic.starts_with('.') {
+        return directives::handle(line.to_string(), interm);
+    }
+
+    if interm.macros.contains_key(&mnemonic) {
+        return macros::expand(&mnemonic, &operands, line, interm);
+    }
+
+    if interm.segment != Segment::Code {
+        error!(
+            format!("instructions are not allowed in the {} segment", interm.segment.name()),
+            interm.location(),
+            line
+        );
+    }
+
+    let ins = match op::select(interm, &mnemonic, &operands) {
+        Ok(ins) => ins,
+        Err(e) => {
+            error!(e, interm.location(), line);
+        }
+    };
+
+    // A location reached twice, as in a file included twice,
+    // shares its form, so only swap one jump for its partner
+    let ins = match interm.relaxed.get(&interm.location()) {
+        Some(&relaxed) if partner(ins.name) == Some(relaxed.name) => relaxed,
+        _ => ins,
+    };
+
+    let stmt = interm.line(Statement::Instruction(ins), line);
+
+    if let Err(e) = interm.occupy(ins.words(), line) {
+        error!(e, interm.location(), line);
+    }
+
+    interm.lines.push(stmt);
+    Ok(())
+}
+
+///
+/// Runs the first pass until the size of every jump settles.
+/// Every `jmp` and `call` starts out as `rjmp` and `rcall`, and
+/// each round grows the short jumps whose target is out of reach.
+/// A jump that had to grow is never shrunk again, which guarantees
+/// the rounds come to an end.
+///
+/// Note: This function will mutate the `interm` parameter.
+///
+pub fn relax(file: &str, interm: &mut Interm) -> Result<(), String> {
+    let symtab = interm.symtab.clone();
+
+    interm.relaxed.clear();
+    first_pass(file, interm)?;
+
+    for line in &interm.lines {
+        match line.stmt {
+            Statement::Instruction(ins) if ins.name == "jmp" => {
+                interm.relaxed.insert(line.location(), interm.instructions["rjmp"][0])
             }
+            Statement::Instruction(ins) if ins.name == "call" => {
+                interm.relaxed.insert(line.location(), interm.instructions["rcall"][0])
+            }
+            _ => continue,
+        };
+    }
 
-            if tokens.len() > 1 {
-                interm.locctr += op::length(&tokens[1].to_lowercase());
+    loop {
+        interm.symtab = symtab.clone();
+        first_pass(file, interm)?;
+
+        let mut changed = false;
+
+        for line in &interm.lines {
+            let long = match line.stmt {
+                Statement::Instruction(ins) if ins.name == "rjmp" => "jmp",
+                Statement::Instruction(ins) if ins.name == "rcall" => "call",
+                _ => continue,
+            };
+
+            let target = match op::get_operands(line.text.to_string(), interm) {
+                Ok(ref v) if v.len() == 1 => v[0].target(),
+                _ => continue,
+            };
+
+            let distance = target - i64::from(line.addr) - 1;
+
+            // Jumps the device cannot grow are left for the second
+            // pass to report as out of range
+            if !(-2048..=2047).contains(&distance) && supported(long, interm) {
+                interm.relaxed.insert(line.location(), interm.instructions[long][0]);
+                changed = true;
             }
-        } else {
-            interm.locctr += op::length(&tokens[0].to_lowercase());
         }
+
+        if !changed {
+            return Ok(());
+        }
+    }
+}
+
+///
+/// Returns the other size of a jump or call
+///
+fn partner(name: &str) -> Option<&'static str> {
+    match name {
+        "jmp" => Some("rjmp"),
+        "rjmp" => Some("jmp"),
+        "call" => Some("rcall"),
+        "rcall" => Some("call"),
+        _ => None,
     }
+}
 
-    Ok(())
+///
+/// Checks whether the device has the instruction `name`
+///
+fn supported(name: &str, interm: &Interm) -> bool {
+    let ins = interm.instructions[name][0];
+    interm.device.as_ref().map(|d| d.supports(ins)).unwrap_or(true)
+}
+
+///
+/// Points an out of range rjmp or rcall to its long form,
+/// unless the device lacks it as the ATmega8 does
+///
+fn suggest(ins: &op::Instruction, error: String, interm: &Interm) -> String {
+    match partner(ins.name) {
+        Some(long) if ins.name.starts_with('r') && error.starts_with("target of") && supported(long, interm) => {
+            format!("{} (use \"{}\" instead)", error, long)
+        }
+        _ => error,
+    }
 }
 
 ///
@@ -108,35 +732,776 @@ pub fn first_pass(file: &String, interm: &mut Interm) -> Result<(), String> {
 ///
 /// Note: This function will mutate the `interm` parameter.
 ///
-pub fn second_pass(file: &String, interm: &mut Interm) -> Result<(), String> {
+pub fn second_pass(interm: &mut Interm) -> Result<(), String> {
     interm.reset_counters();
 
-    'outer: for line in file.lines() {
-        let line = line.to_string();
-        let mut tokens = util::split_string(&line);
+    // Names changed by directives start out with their first
+    // value so that forward references still resolve
+    for i in (0..interm.history.len()).rev() {
+        let change = interm.history[i].1.clone();
+        interm.apply(&change);
+    }
 
-        interm.linectr += 1;
-        println!("{}: {}", interm.linectr, line);
+    let mut changes = 0;
 
-        // Skip blank lines
-        if tokens.len() == 0 {
-            continue;
-        }
+    for i in 0..interm.lines.len() {
+        changes = replay(interm, changes, i);
 
-        // Skip commented lines and assembler directives
-        match &tokens[0][..1] {
-            ";" | "." | "#" => continue,
-            _ => {}
-        }
+        let code = {
+            let line = &interm.lines[i];
 
-        match op::get_operands(line.to_string(), interm) {
-            Ok(v) => println!("Operands: {:?}", v),
-            Err(e) => {
-                // error!(e, interm.linectr, line);
-                println!("Operands Error: {}", e);
+            let result = match line.stmt {
+                Statement::Instruction(ins) => op::get_operands(line.text.to_string(), interm)
+                    .and_then(|operands| {
+                        if let Some(ref device) = interm.device {
+                            device.check(ins, &operands)?;
+                        }
+
+                        op::parse(ins, &operands, line.addr).map_err(|e| suggest(ins, e, interm))
+                    }),
+                Statement::Data(size) => directives::data(line, size, interm),
+            };
+
+            match result {
+                Ok(c) => c,
+                Err(e) => {
+                    error!(e, line.location(), line.text);
+                }
             }
-        }
+        };
+
+        interm.lines[i].opcode = Some(code);
     }
 
+    replay(interm, changes, interm.lines.len());
     Ok(())
 }
+
+///
+/// Applies the recorded name changes, starting at `from`, that
+/// come before line `index`. Returns the index of the first
+/// change that was not applied.
+///
+fn replay(interm: &mut Interm, from: usize, index: usize) -> usize {
+    let mut next = from;
+
+    while next < interm.history.len() && interm.history[next].0 <= index {
+        let change = interm.history[next].1.clone();
+        interm.apply(&change);
+        next += 1;
+    }
+
+    next
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn assemble(file: &str, relaxed: bool) -> Interm {
+        let mut interm = Interm::new();
+
+        if relaxed {
+            relax(file, &mut interm).unwrap();
+        } else {
+            first_pass(file, &mut interm).unwrap();
+        }
+
+        second_pass(&mut interm).unwrap();
+        interm
+    }
+
+    fn names(interm: &Interm) -> Vec<&'static str> {
+        interm.lines.iter().map(|line| match line.stmt {
+            Statement::Instruction(ins) => ins.name,
+            Statement::Data(_) => ".data",
+        }).collect()
+    }
+
+    #[test]
+    fn test_relax() {
+        let near = "start: nop\njmp start\ncall start\njmp end\nend: nop";
+        let interm = assemble(near, false);
+        assert_eq!(names(&interm), vec!["nop", "jmp", "call", "jmp", "nop"]);
+        assert_eq!(interm.symtab["end"], 7);
+
+        let interm = assemble(near, true);
+        assert_eq!(names(&interm), vec!["nop", "rjmp", "rcall", "rjmp", "nop"]);
+        assert_eq!(interm.symtab["end"], 4);
+        assert_eq!(interm.lines[1].opcode, Some(op::ObjectCode::Short(0xcffe)));
+
+        let far = format!("jmp end\nrjmp end\n{}end: nop", "nop\n".repeat(2048));
+        let interm = assemble(&far, true);
+        assert_eq!(names(&interm)[..2], ["jmp", "jmp"]);
+        assert_eq!(interm.symtab["end"], 2052);
+
+        let edge = format!("jmp end\n{}end: nop", "nop\n".repeat(2047));
+        let interm = assemble(&edge, true);
+        assert_eq!(names(&interm)[0], "rjmp");
+        assert_eq!(interm.lines[0].opcode, Some(op::ObjectCode::Short(0xc7ff)));
+
+        // Shrinking the first jump adds a line before the last one
+        let shifted = "a: jmp b\nb:\n.if b - a == 1\nnop\n.endif\nldi r16, 1\njmp a";
+        let interm = assemble(shifted, true);
+        assert_eq!(names(&interm), vec!["rjmp", "nop", "ldi", "rjmp"]);
+        assert_eq!(interm.lines[3].opcode, Some(op::ObjectCode::Short(0xcffc)));
+    }
+
+    fn codes(interm: &Interm) -> Vec<op::ObjectCode> {
+        interm.lines.iter().map(|line| line.opcode.clone().unwrap()).collect()
+    }
+
+    fn fails(file: &str) -> String {
+        let mut interm = Interm::new();
+        first_pass(file, &mut interm).and_then(|_| second_pass(&mut interm)).unwrap_err()
+    }
+
+    #[test]
+    fn test_equ_set_undef() {
+        let file = ".equ PORTB = 0x05\n\
+                    .set count = 1\n\
+                    out PORTB, r16\n\
+                    ldi r16, count\n\
+                    .set count = count + 1\n\
+                    ldi r16, count\n\
+                    .undef PORTB\n\
+                    .EQU PORTB = 0x08\n\
+                    out PORTB, r16";
+        let interm = assemble(file, false);
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Short(0xb905),
+            op::ObjectCode::Short(0xe001),
+            op::ObjectCode::Short(0xe002),
+            op::ObjectCode::Short(0xb908),
+        ]);
+        assert_eq!(interm.symtab["count"], 2);
+
+        let forward = "ldi r16, LIMIT\n.equ LIMIT = 10";
+        assert_eq!(codes(&assemble(forward, false)), vec![op::ObjectCode::Short(0xe00a)]);
+
+        assert_eq!(fails(".equ A = 1\n.equ A = 2"),
+                   "Error parsing .equ directive: redefinition of symbol \"A\"\nLine 2:\n\n.equ A = 2");
+        assert_eq!(fails(".equ A = 1\n.set A = 2"),
+                   "Error parsing .set directive: \"A\" is a constant and cannot be changed with .set\nLine 2:\n\n.set A = 2");
+        assert_eq!(fails(".set A = 1\n.equ A = 2"),
+                   "Error parsing .equ directive: redefinition of symbol \"A\"\nLine 2:\n\n.equ A = 2");
+        assert_eq!(fails(".undef A"),
+                   "Error parsing .undef directive: undefined symbol \"A\"\nLine 1:\n\n.undef A");
+        assert_eq!(fails(".equ A 2"),
+                   "Error parsing .equ directive: expected NAME = value\nLine 1:\n\n.equ A 2");
+        assert_eq!(fails(".equ A = 1\n.undef A\nldi r16, A"),
+                   "Error: Undefined symbol A\nLine 3:\n\nldi r16, A");
+        assert_eq!(fails("start: nop\nstart: nop"),
+                   "Error: redefinition of symbol \"start\"\nLine 2:\n\nstart: nop");
+        assert_eq!(fails(": nop"), "Error: missing label name\nLine 1:\n\n: nop");
+    }
+
+    #[test]
+    fn test_def() {
+        let file = ".def temp = r16\n\
+                    ldi temp, 5\n\
+                    .DEF Temp = r17\n\
+                    ldi TEMP, 5\n\
+                    .def acc = temp\n\
+                    mov acc, r0\n\
+                    ldi ZL, 0x34\n\
+                    .undef temp\n\
+                    .equ temp = 2\n\
+                    ldi r16, temp";
+        let interm = assemble(file, false);
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Short(0xe005),
+            op::ObjectCode::Short(0xe015),
+            op::ObjectCode::Short(0x2d10),
+            op::ObjectCode::Short(0xe3e4),
+            op::ObjectCode::Short(0xe002),
+        ]);
+        assert_eq!(interm.diagnostics, vec![String::from(
+            "Warning: redefinition of register alias \"Temp\" (was r16)\nLine 3:\n\n.DEF Temp = r17"
+        )]);
+
+        assert_eq!(fails(".def temp = 5"),
+                   "Error parsing .def directive: \"5\" is not a register\nLine 1:\n\n.def temp = 5");
+        assert_eq!(fails(".def temp = r40"),
+                   "Error parsing .def directive: Register number out of range (40)\nLine 1:\n\n.def temp = r40");
+        assert_eq!(fails(".def temp = r16\n.undef temp\nldi temp, 1"),
+                   "Error: Undefined symbol temp\nLine 3:\n\nldi temp, 1");
+    }
+
+    #[test]
+    fn test_data() {
+        let file = "start: nop\n\
+                    table: .dw start, end, -1\n\
+                    .db \"AB\", 'C', 0xff, -1, 0\n\
+                    text: .DB \"hi;\\n\"\n\
+                    .dd 0x12345678\n\
+                    .dq -2\n\
+                    .dq 0x1122334455667788, 0xFFFFFFFFFFFFFFFF\n\
+                    end: rjmp table";
+        let interm = assemble(file, false);
+        assert_eq!(names(&interm), vec!["nop", ".data", ".data", ".data", ".data", ".data", ".data", "rjmp"]);
+        assert_eq!(interm.symtab["table"], 1);
+        assert_eq!(interm.symtab["text"], 7);
+        assert_eq!(interm.symtab["end"], 23);
+        assert_eq!(codes(&interm)[1..], [
+            op::ObjectCode::Data(vec![0x00, 0x00, 0x17, 0x00, 0xff, 0xff]),
+            op::ObjectCode::Data(vec![0x41, 0x42, 0x43, 0xff, 0xff, 0x00]),
+            op::ObjectCode::Data(vec![0x68, 0x69, 0x3b, 0x0a]),
+            op::ObjectCode::Data(vec![0x78, 0x56, 0x34, 0x12]),
+            op::ObjectCode::Data(vec![0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            op::ObjectCode::Data(vec![
+                0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ]),
+            op::ObjectCode::Short(0xcfe9),
+        ]);
+        assert!(interm.diagnostics.is_empty());
+
+        let interm = assemble(".db \"a\\\"b\", 0\n.db \"a\\\";b\", 0\n.db \"x\\\", y\", 0", false);
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Data(vec![0x61, 0x22, 0x62, 0x00]),
+            op::ObjectCode::Data(vec![0x61, 0x22, 0x3b, 0x62, 0x00, 0x00]),
+            op::ObjectCode::Data(vec![0x78, 0x22, 0x2c, 0x20, 0x79, 0x00]),
+        ]);
+
+        let interm = assemble(".db 1, 2, 3\nnext: nop", false);
+        assert_eq!(interm.symtab["next"], 2);
+        assert_eq!(codes(&interm)[0], op::ObjectCode::Data(vec![1, 2, 3, 0]));
+        assert_eq!(interm.diagnostics, vec![String::from(
+            "Warning: odd number of bytes in .db, padded with a zero byte\nLine 1:\n\n.db 1, 2, 3"
+        )]);
+
+        assert_eq!(fails("ldi r16, 0xFFFFFFFFFFFFFFFF"),
+                   "Error: Number out of range (0xFFFFFFFFFFFFFFFF)\nLine 1:\n\nldi r16, 0xFFFFFFFFFFFFFFFF");
+        assert_eq!(fails(".dd 0xFFFFFFFFFFFFFFFF"),
+                   "Error: Number out of range (0xFFFFFFFFFFFFFFFF)\nLine 1:\n\n.dd 0xFFFFFFFFFFFFFFFF");
+        assert_eq!(fails(".db 256"),
+                   "Error: Value out of range (256), must fit in 8 bits\nLine 1:\n\n.db 256");
+        assert_eq!(fails(".dw -32769"),
+                   "Error: Value out of range (-32769), must fit in 16 bits\nLine 1:\n\n.dw -32769");
+        assert_eq!(fails(".dw \"AB\""),
+                   "Error parsing .dw directive: strings are only allowed in .db\nLine 1:\n\n.dw \"AB\"");
+        assert_eq!(fails(".db"),
+                   "Error parsing .db directive: no values provided\nLine 1:\n\n.db");
+        assert_eq!(fails(".db \"AB"),
+                   "Error parsing .db directive: Unterminated string literal\nLine 1:\n\n.db \"AB");
+    }
+
+    #[test]
+    fn test_segments() {
+        let file = ".dseg\n\
+                    buffer:\n\
+                    .eseg\n\
+                    config: .db 1, 2, 3\n\
+                    limit: .dw 0x1234\n\
+                    .cseg\n\
+                    start: nop\n\
+                    .org 0x10\n\
+                    main: rjmp start\n\
+                    .eseg\n\
+                    .org 0x20\n\
+                    last: .db 4\n\
+                    .cseg\n\
+                    end: nop";
+        let interm = assemble(file, false);
+        assert_eq!(interm.symtab["buffer"], 0x60);
+        assert_eq!(interm.symtab["config"], 0);
+        assert_eq!(interm.symtab["limit"], 3);
+        assert_eq!(interm.symtab["start"], 0);
+        assert_eq!(interm.symtab["main"], 0x10);
+        assert_eq!(interm.symtab["last"], 0x20);
+        assert_eq!(interm.symtab["end"], 0x11);
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Data(vec![1, 2, 3]),
+            op::ObjectCode::Data(vec![0x34, 0x12]),
+            op::ObjectCode::Short(0x0000),
+            op::ObjectCode::Short(0xcfef),
+            op::ObjectCode::Data(vec![4]),
+            op::ObjectCode::Short(0x0000),
+        ]);
+        assert_eq!(interm.lines[0].seg, Segment::Eeprom);
+        assert_eq!(interm.lines[2].seg, Segment::Code);
+        assert!(interm.diagnostics.is_empty());
+
+        assert_eq!(fails(".org -1"), "Error parsing .org directive: negative address (-1)\nLine 1:\n\n.org -1");
+        assert_eq!(fails(".org 0xFFFFFFFF + 1"),
+                   "Error parsing .org directive: address 0x100000000 is out of range, the code segment ends at \
+                    0xfffffffe\nLine 1:\n\n.org 0xFFFFFFFF + 1");
+        assert_eq!(fails(".device ATmega328P\n.org 0x4000"),
+                   "Error parsing .org directive: address 0x4000 is out of range, the code segment ends at 0x3fff\n\
+                    Line 2:\n\n.org 0x4000");
+        assert_eq!(fails(".device ATmega328P\n.eseg\n.org 1024"),
+                   "Error parsing .org directive: address 0x400 is out of range, the EEPROM segment ends at 0x3ff\n\
+                    Line 3:\n\n.org 1024");
+        assert_eq!(fails(".device ATtiny10\n.eseg\n.org 0"),
+                   "Error parsing .org directive: address 0x0 is out of range, ATtiny10 has no EEPROM segment\n\
+                    Line 3:\n\n.org 0");
+        assert!(assemble(".device ATmega328P\n.org 0x3fff\nnop\n.eseg\n.org 1023\n.db 1", false).diagnostics.is_empty());
+
+        assert_eq!(fails(".dseg\nnop"),
+                   "Error: instructions are not allowed in the data segment\nLine 2:\n\nnop");
+        assert_eq!(fails(".eseg\nnop"),
+                   "Error: instructions are not allowed in the EEPROM segment\nLine 2:\n\nnop");
+        assert_eq!(fails(".dseg\n.db 1"),
+                   "Error parsing .db directive: data cannot be placed in the data segment, use .byte\nLine 2:\n\n.db 1");
+    }
+
+    #[test]
+    fn test_byte() {
+        let file = ".equ SIZE = 32\n\
+                    .dseg\n\
+                    buffer: .byte SIZE\n\
+                    count: .BYTE 2\n\
+                    flag: .byte 1\n\
+                    .cseg\n\
+                    lds r16, flag";
+        let interm = assemble(file, false);
+        assert_eq!(interm.symtab["buffer"], 0x60);
+        assert_eq!(interm.symtab["count"], 0x80);
+        assert_eq!(interm.symtab["flag"], 0x82);
+        assert_eq!(interm.ram_used(), 35);
+        assert_eq!(codes(&interm), vec![op::ObjectCode::Long(0x9100_0082)]);
+        assert_eq!(assemble(".dseg\n.byte 3", false).ram_used(), 3);
+        assert_eq!(assemble(".device ATmega328P\n.dseg\n.org 0x300\n.byte 4\n.org 0x200\n.byte 1", false).ram_used(), 5);
+        assert_eq!(assemble(".dseg\n.byte 4\n.overlap\n.org 0x62\n.byte 4", false).ram_used(), 6);
+
+        let mut interm = Interm::new();
+        interm.sram_size = Some(64);
+        assert_eq!(first_pass(".dseg\na: .byte 60\nb: .byte 4\nc: .byte 1", &mut interm),
+                   Err(String::from("Error parsing .byte directive: SRAM overflow by 1 bytes, SRAM ends at 0x9f\nLine 4:\n\nc: .byte 1")));
+
+        assert_eq!(fails(".byte 1"),
+                   "Error parsing .byte directive: only allowed in the data segment\nLine 1:\n\n.byte 1");
+        assert_eq!(fails(".dseg\n.byte -1"),
+                   "Error parsing .byte directive: negative size (-1)\nLine 2:\n\n.byte -1");
+        assert_eq!(fails(".dseg\n.byte N\n.equ N = 1"),
+                   "Error parsing .byte directive: Undefined symbol N\nLine 2:\n\n.byte N");
+    }
+
+    fn include(file: &str, dirs: &[&str]) -> Result<Interm, String> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/include");
+        let mut interm = Interm::new();
+        interm.path = Some(dir.join("main.asm"));
+        interm.include_dirs = dirs.iter().map(|d| dir.join(d)).collect();
+
+        first_pass(file, &mut interm)?;
+        second_pass(&mut interm)?;
+        Ok(interm)
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/include");
+        let file = "start: nop\n\
+                    .include \"defs.inc\"\n\
+                    .INCLUDE \"util.inc\" ; found with -I\n\
+                    ldi acc, ANSWER + BIT + LIBVAL\n\
+                    end: nop";
+        let interm = include(file, &["lib"]).unwrap();
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Short(0x0000),
+            op::ObjectCode::Short(0xe304),
+            op::ObjectCode::Short(0x0000),
+        ]);
+        assert_eq!(interm.symtab["end"], 2);
+        assert_eq!(interm.lines[1].location(), "4");
+        assert!(interm.includes.is_empty());
+
+        let interm = include(".include \"bad.inc\"\nnop", &[]);
+        assert_eq!(interm.err().unwrap(), format!(
+            "Error: Undefined symbol NOPE\nLine 2 of {}:\n\nldi r16, NOPE",
+            dir.join("bad.inc").display()
+        ));
+
+        let interm = include("nop\n.include \"loop_a.inc\"", &[]);
+        assert_eq!(interm.err().unwrap(), format!(
+            "Error parsing .include directive: recursive inclusion of \"{}\"\nLine 2 of {}:\n\n.include \"loop_a.inc\"",
+            dir.join("loop_a.inc").display(),
+            dir.join("loop_b.inc").display()
+        ));
+
+        let interm = include(".include \"open_if.inc\"\n.endif", &[]);
+        assert_eq!(interm.err().unwrap(), format!(
+            "Error: missing .endif\nLine 2 of {}:\n\n.if 1",
+            dir.join("open_if.inc").display()
+        ));
+
+        assert_eq!(include(".include \"util.inc\"", &[]).err().unwrap(),
+                   "Error parsing .include directive: cannot find \"util.inc\"\nLine 1:\n\n.include \"util.inc\"");
+        assert_eq!(include(".include defs.inc", &[]).err().unwrap(),
+                   "Error parsing .include directive: expected a quoted file name\nLine 1:\n\n.include defs.inc");
+    }
+
+    #[test]
+    fn test_device() {
+        let file = ".include \"m2560def.inc\"\n\
+                    .dseg\n\
+                    buffer: .byte 16\n\
+                    .cseg\n\
+                    sts DDRL, r16\n\
+                    out PORTB, r16\n\
+                    ldi ZL, LOW(RAMEND)";
+        let interm = include(file, &["../inc"]).unwrap();
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Long(0x9300_010a),
+            op::ObjectCode::Short(0xb905),
+            op::ObjectCode::Short(0xefef),
+        ]);
+        assert_eq!(interm.symtab["buffer"], 0x200);
+        assert_eq!(interm.sram_size, Some(8192));
+        assert!(interm.diagnostics.is_empty());
+
+        let device = interm.device.unwrap();
+        assert_eq!(device.name, "ATmega2560");
+        assert_eq!(device.core, Some(device::Core::V3));
+        assert_eq!(device.flash_size, 256 * 1024);
+        assert_eq!(device.eeprom_size, 4096);
+        assert_eq!(device.vectors_size, 114);
+        assert_eq!(device.vectors[0], device::Vector { name: String::from("INT0addr"), addr: 2 });
+
+        let ddrl = device.registers.iter().find(|r| r.name == "DDRL").unwrap();
+        assert_eq!((ddrl.addr, ddrl.memory_mapped), (0x10a, true));
+        let portb = device.registers.iter().find(|r| r.name == "PORTB").unwrap();
+        assert_eq!((portb.addr, portb.memory_mapped), (0x05, false));
+    }
+
+    #[test]
+    fn test_device_support() {
+        let interm = assemble(".device ATmega328P\nmul r16, r17\njmp 0x3fff", false);
+        assert_eq!(interm.device.as_ref().unwrap().name, "ATmega328P");
+        assert_eq!(interm.sram_start, 0x100);
+
+        assert_eq!(fails(".device ATtiny13\nmul r16, r17"),
+                   "Error: mul Rd, Rr is not supported on ATtiny13\nLine 2:\n\nmul r16, r17");
+        assert_eq!(fails(".DEVICE atmega8\njmp 0"),
+                   "Error: jmp k is not supported on ATmega8\nLine 2:\n\njmp 0");
+        assert_eq!(fails(".device ATmega328P\ncall 0x4000"),
+                   "Error: Address 0x4000 is outside the flash of ATmega328P\nLine 2:\n\ncall 0x4000");
+        assert_eq!(fails(".device ATtiny10\nmov r0, r16"),
+                   "Error: r0 is not available on ATtiny10\nLine 2:\n\nmov r0, r16");
+        let interm = assemble(".device ATtiny10\nlds r16, 0x40\nsts 0x80, r17\nlds r20, 0xbf\nnop", false);
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Short(0xa100),
+            op::ObjectCode::Short(0xa810),
+            op::ObjectCode::Short(0xa64f),
+            op::ObjectCode::Short(0x0000),
+        ]);
+        assert_eq!(fails(".device ATtiny10\nlds r16, 0x100"),
+                   "Error: operand 2 of \"lds\" out of range (256), must be 64..191\nLine 2:\n\nlds r16, 0x100");
+        assert_eq!(fails(".device ATtiny10\nldd r16, Y+1"),
+                   "Error: ldd Rd, Y+q is not supported on ATtiny10\nLine 2:\n\nldd r16, Y+1");
+        assert_eq!(fails(".device ATtiny13\n.dseg\n.byte 65"),
+                   "Error parsing .byte directive: SRAM overflow by 1 bytes, SRAM ends at 0x9f\nLine 3:\n\n.byte 65");
+        assert_eq!(fails(".device ATmega9000"),
+                   "Error parsing .device directive: unknown device \"ATmega9000\"\nLine 1:\n\n.device ATmega9000");
+        assert_eq!(fails(".device ATtiny13\n.device ATmega8"),
+                   "Error parsing .device directive: device is already set to ATtiny13\nLine 2:\n\n.device ATmega8");
+
+        assert_eq!(include(".device ATmega8\n.include \"m2560def.inc\"", &["../inc"]).err().unwrap(),
+                   "Error parsing .include directive: device is already set to ATmega8\nLine 2:\n\n.include \"m2560def.inc\"");
+
+        // Without jmp the relaxation pass leaves far jumps short
+        let far = format!(".device ATmega8\nrjmp end\n{}end: nop", "nop\n".repeat(2048));
+        let mut interm = Interm::new();
+        relax(&far, &mut interm).unwrap();
+        assert_eq!(names(&interm)[0], "rjmp");
+        assert_eq!(second_pass(&mut interm).unwrap_err(),
+                   "Error: target of \"rjmp\" out of range: distance is 2048 words, limit is -2048..+2047\n\
+                    Line 2:\n\nrjmp end");
+
+        let far = format!(".device ATmega328P\nrcall end\n{}end: nop", "nop\n".repeat(2048));
+        assert_eq!(fails(&far),
+                   "Error: target of \"rcall\" out of range: distance is 2048 words, limit is -2048..+2047 \
+                    (use \"call\" instead)\nLine 2:\n\nrcall end");
+    }
+
+    #[test]
+    fn test_macros() {
+        let file = ".macro ldi16\n\
+                    ldi @0, LOW(@2)\n\
+                    ldi @1, HIGH(@2)\n\
+                    .endm\n\
+                    .MACRO delay\n\
+                    ldi16 r24, r25, @0\n\
+                    loop: sbiw r24, 1\n\
+                    brne loop\n\
+                    .ENDMACRO\n\
+                    start: ldi16 r16, r17, 0x1234\n\
+                    delay 1000\n\
+                    DELAY 2\n\
+                    rjmp start";
+        let interm = assemble(file, false);
+        assert_eq!(codes(&interm), vec![
+            op::ObjectCode::Short(0xe304),
+            op::ObjectCode::Short(0xe112),
+            op::ObjectCode::Short(0xee88),
+            op::ObjectCode::Short(0xe093),
+            op::ObjectCode::Short(0x9701),
+            op::ObjectCode::Short(0xf7f1),
+            op::ObjectCode::Short(0xe082),
+            op::ObjectCode::Short(0xe090),
+            op::ObjectCode::Short(0x9701),
+            op::ObjectCode::Short(0xf7f1),
+            op::ObjectCode::Short(0xcff5),
+        ]);
+        assert_eq!(interm.symtab["loop?2"], 4);
+        assert_eq!(interm.symtab["loop?4"], 8);
+        assert!(!interm.symtab.contains_key("loop"));
+
+        // User names cannot take the form of a local label
+        let file = ".macro wait\nloop: dec r16\nbrne loop\n.endm\nloop__1: wait\nrjmp loop__1";
+        let interm = assemble(file, false);
+        assert_eq!(interm.symtab["loop__1"], 0);
+        assert_eq!(interm.symtab["loop?1"], 0);
+        assert_eq!(fails(".macro wait\nloop: dec r16\n.endm\nwait\nloop?1: nop"),
+                   "Error: invalid label name \"loop?1\"\nLine 5:\n\nloop?1: nop");
+        assert_eq!(fails(".macro m\nloop?1: nop\n.endm\nm\nm"),
+                   "Error: invalid label name \"loop?1\"\nLine 2 in macro m, called from line 5:\n\nloop?1: nop");
+
+        let file = ".macro load\n\
+                    ldi @0, @1\n\
+                    .endm\n\
+                    .macro twice\n\
+                    load @0, @1\n\
+                    load @0, @1\n\
+                    .endm\n\
+                    twice r16, UNKNOWN";
+        assert_eq!(fails(file),
+                   "Error: Undefined symbol UNKNOWN\nLine 2 in macro load, called from line 5 in macro twice, \
+                    called from line 8:\n\nldi r16, UNKNOWN");
+
+        assert_eq!(fails(".macro m\nmov @0, @1\n.endm\nnop\nm r1"),
+                   "Error: @1 used but only 1 arguments were given\nLine 2 in macro m, called from line 5:\n\nmov @0, @1");
+        assert_eq!(fails(".macro forever\nforever\n.endm\nforever"),
+                   "Error: macro \"forever\" nested more than 16 levels deep\nLine 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 2 in macro forever, \
+                    called from line 2 in macro forever, called from line 4:\n\nforever");
+        assert_eq!(fails("nop\n.macro m\nnop"),
+                   "Error: missing .endm for macro \"m\"\nLine 2:\n\n.macro m");
+        assert_eq!(fails(".macro m\n.macro n\n.endm"),
+                   "Error: macro definitions cannot be nested\nLine 2:\n\n.macro n");
+        assert_eq!(fails(".endm"),
+                   "Error parsing .endm directive: no macro is being defined\nLine 1:\n\n.endm");
+        assert_eq!(fails(".macro m\n.endm\n.macro M\n.endm"),
+                   "Error parsing .macro directive: macro \"M\" is already defined\nLine 3:\n\n.macro M");
+    }
+
+    #[test]
+    fn test_conditions() {
+        let file = ".equ REVISION = 2\n\
+                    .equ DEBUG = 0\n\
+                    .if REVISION == 1\n\
+                    one: ldi r16, 1\n\
+                    .elif REVISION == 2\n\
+                    two: ldi r16, 2\n\
+                    .IFDEF DEBUG\n\
+                    .if DEBUG\n\
+                    call trace\n\
+                    .else\n\
+                    nop\n\
+                    .endif\n\
+                    .endif\n\
+                    .else\n\
+                    other: bogus r99\n\
+                    .if UNDEFINED\n\
+                    .endif\n\
+                    .endif\n\
+                    .ifndef DEBUG\n\
+                    nop\n\
+                    .endif\n\
+                    end: nop";
+        let interm = assemble(file, false);
+        assert_eq!(names(&interm), vec!["ldi", "nop", "nop"]);
+        assert_eq!(interm.symtab["two"], 0);
+        assert_eq!(interm.symtab["end"], 2);
+        assert!(!interm.symtab.contains_key("one"));
+        assert!(!interm.symtab.contains_key("other"));
+
+        let file = ".macro pick\n\
+                    .if @0\n\
+                    ldi r16, 1\n\
+                    .else\n\
+                    ldi r16, 0\n\
+                    .endif\n\
+                    .endm\n\
+                    pick 1\n\
+                    pick 0";
+        assert_eq!(codes(&assemble(file, false)), vec![
+            op::ObjectCode::Short(0xe001),
+            op::ObjectCode::Short(0xe000),
+        ]);
+
+        assert_eq!(fails("nop\n.if 1\n.if 0\n.endif\nnop"),
+                   "Error: missing .endif\nLine 2:\n\n.if 1");
+        assert_eq!(fails(".if 1\n.macro m\n.if 0\nnop\n.endm\nm\n.endif\n.endif"),
+                   "Error: missing .endif\nLine 3 in macro m, called from line 6:\n\n.if 0");
+        assert_eq!(fails(".endif"),
+                   "Error parsing .endif directive: no .if is open\nLine 1:\n\n.endif");
+        assert_eq!(fails(".if 1\n.else\n.else\n.endif"),
+                   "Error parsing .else directive: .else is already used\nLine 3:\n\n.else");
+        assert_eq!(fails(".if 1\n.else\n.elif 1\n.endif"),
+                   "Error parsing .elif directive: .elif after .else\nLine 3:\n\n.elif 1");
+        assert_eq!(fails(".if MISSING\n.endif"),
+                   "Error parsing .if directive: Undefined symbol MISSING\nLine 1:\n\n.if MISSING");
+        assert_eq!(fails(".ifdef\n.endif"),
+                   "Error parsing .ifdef directive: no condition provided\nLine 1:\n\n.ifdef");
+    }
+
+    #[test]
+    fn test_user_diagnostics() {
+        let file = ".equ F_CPU = 16000000\n\
+                    .message \"clock is \", F_CPU / 1000000, \" MHz\"\n\
+                    .if F_CPU > 8000000\n\
+                    .warning \"fast clock\"\n\
+                    .endif\n\
+                    .if F_CPU > 20000000\n\
+                    .error \"clock too fast\"\n\
+                    .endif\n\
+                    nop";
+        let interm = assemble(file, false);
+        assert_eq!(interm.diagnostics, vec![
+            String::from("Message: clock is 16 MHz\nLine 2:\n\n.message \"clock is \", F_CPU / 1000000, \" MHz\""),
+            String::from("Warning: fast clock\nLine 4:\n\n.warning \"fast clock\""),
+        ]);
+
+        assert_eq!(fails(".equ F_CPU = 24000000\n.if F_CPU > 20000000\n.error \"clock too fast: \", F_CPU\n.endif"),
+                   "Error: clock too fast: 24000000\nLine 3:\n\n.error \"clock too fast: \", F_CPU");
+        assert_eq!(fails(".macro check\n.error \"bad \", @0\n.endm\ncheck 3"),
+                   "Error: bad 3\nLine 2 in macro check, called from line 4:\n\n.error \"bad \", 3");
+        assert_eq!(fails(".message \"x is \", X"),
+                   "Error parsing .message directive: Undefined symbol X\nLine 1:\n\n.message \"x is \", X");
+        assert_eq!(fails(".error"),
+                   "Error parsing .error directive: no text provided\nLine 1:\n\n.error");
+    }
+
+    #[test]
+    fn test_exit() {
+        let interm = assemble("nop\n.exit 0\nnop\n.EXIT\nbogus r99\n.if 1", false);
+        assert_eq!(names(&interm), vec!["nop", "nop"]);
+
+        let interm = assemble(".macro stop\n.if @0\n.exit\n.endif\nnop\n.endm\nstop 0\nstop 1\nnop", false);
+        assert_eq!(names(&interm), vec!["nop"]);
+
+        // .exit in an included file only ends that file
+        let file = ".include \"exit.inc\"\n\
+                    ldi r16, BEFORE\n\
+                    .ifdef AFTER\n\
+                    bogus\n\
+                    .endif";
+        let interm = include(file, &[]).unwrap();
+        assert_eq!(codes(&interm), vec![op::ObjectCode::Short(0xe001)]);
+
+        assert_eq!(fails(".exit X"),
+                   "Error parsing .exit directive: Undefined symbol X\nLine 1:\n\n.exit X");
+    }
+
+    #[test]
+    fn test_overlap() {
+        let file = "rjmp main\n\
+                    .org 0x2\n\
+                    rjmp timer\n\
+                    .org 0x4\n\
+                    main: nop\n\
+                    timer: reti\n\
+                    .dseg\n\
+                    .org 0x100\n\
+                    .byte 2\n\
+                    .org 0x102\n\
+                    .byte 1";
+        let interm = assemble(file, false);
+        assert_eq!(names(&interm), vec!["rjmp", "rjmp", "nop", "reti"]);
+
+        assert_eq!(fails("rjmp main\n.org 0x2\nrjmp timer\nmain: nop\n.org 0x2\ntimer: reti"),
+                   "Error: address 0x2 of the code segment is already used by line 3: rjmp timer\nLine 6:\n\ntimer: reti");
+        assert_eq!(fails("jmp 0\n.org 0x1\nnop"),
+                   "Error: address 0x1 of the code segment is already used by line 1: jmp 0\nLine 3:\n\nnop");
+        assert_eq!(fails(".org 0x10\n.dw 1, 2\n.org 0xf\n.dw 3, 4"),
+                   "Error parsing .dw directive: address 0x10 of the code segment is already used by line 2: .dw 1, 2\n\
+                    Line 4:\n\n.dw 3, 4");
+        assert_eq!(fails(".dseg\nbuffer: .byte 4\n.org 0x62\n.byte 1"),
+                   "Error parsing .byte directive: address 0x62 of the data segment is already used by line 2: \
+                    buffer: .byte 4\nLine 4:\n\n.byte 1");
+
+        // Segments are tracked apart
+        let interm = assemble("nop\n.eseg\n.db 1, 2", false);
+        assert_eq!(names(&interm), vec!["nop", ".data"]);
+
+        let file = "rjmp main\n\
+                    .overlap\n\
+                    .org 0x0\n\
+                    reti\n\
+                    .nooverlap\n\
+                    main: nop";
+        let interm = assemble(file, false);
+        assert_eq!(names(&interm), vec!["rjmp", "reti", "nop"]);
+
+        assert_eq!(fails(".org 0xFFFFFFFE\njmp 0"),
+                   "Error: address out of range, the code segment ends at 0xfffffffe\nLine 2:\n\njmp 0");
+        assert_eq!(fails(".device ATtiny13\n.org 0x1ff\nnop\nnop"),
+                   "Error: address out of range, the code segment ends at 0x1ff\nLine 4:\n\nnop");
+        assert_eq!(fails(".device ATtiny13\n.eseg\n.org 63\n.db 1, 2"),
+                   "Error parsing .db directive: address out of range, the EEPROM segment ends at 0x3f\n\
+                    Line 4:\n\n.db 1, 2");
+        assert_eq!(fails(".device ATtiny10\n.eseg\n.db 1"),
+                   "Error parsing .db directive: address out of range, ATtiny10 has no EEPROM segment\n\
+                    Line 3:\n\n.db 1");
+        assert_eq!(fails(".dseg\n.byte 0xFFFFFFFF"),
+                   "Error parsing .byte directive: address out of range, the data segment ends at 0xfffffffe\n\
+                    Line 2:\n\n.byte 0xFFFFFFFF");
+
+        assert_eq!(fails(".overlap 1"),
+                   "Error parsing .overlap directive: unexpected argument \"1\"\nLine 1:\n\n.overlap 1");
+    }
+
+    #[test]
+    fn test_listing() {
+        let file = ".nolist\n\
+                    .equ COUNT = 3\n\
+                    .list\n\
+                    .macro twice\n\
+                    @0\n\
+                    @0\n\
+                    .endm\n\
+                    start: ldi r16, COUNT ; load\n\
+                    twice nop\n\
+                    .listmac\n\
+                    twice inc r16\n\
+                    jmp start\n\
+                    .db \"Hello!\", 0, 1";
+        let mut interm = Interm::new();
+        interm.path = Some(PathBuf::from("main.asm"));
+        interm.listing = Some(listing::Listing::new());
+        first_pass(file, &mut interm).unwrap();
+        second_pass(&mut interm).unwrap();
+
+        assert_eq!(listing::generate(&interm),
+                   "AVR assembler listing of main.asm\n\
+                    \x20   1                                 .nolist\n\
+                    \x20   4                                 .macro twice\n\
+                    \x20   5                                 @0\n\
+                    \x20   6                                 @0\n\
+                    \x20   7                                 .endm\n\
+                    \x20   8  C:000000  e003                 start: ldi r16, COUNT ; load\n\
+                    \x20   9  C:000001  0000 0000            twice nop\n\
+                    \x20  10                                 .listmac\n\
+                    \x20  11                                 twice inc r16\n\
+                    \x20   +  C:000003  9503                 inc r16\n\
+                    \x20   +  C:000004  9503                 inc r16\n\
+                    \x20  12  C:000005  940c 0000            jmp start\n\
+                    \x20  13  C:000007  48 65 6c 6c 6f 21    .db \"Hello!\", 0, 1\n\
+                    \x20                00 01\n\
+                    \n\
+                    Symbols:\n\
+                    \n\
+                    COUNT                    0x0003     defined at 2\n\
+                    \x20                                   used at 8\n\
+                    start                    0x0000     defined at 8\n\
+                    \x20                                   used at 12\n");
+    }
+}
@@ -5,391 +5,862 @@
 use std::collections::HashMap;
 use assembler::Interm;
 
+use expr;
 use util;
 
+use self::Class::*;
+use self::Kind::*;
+use self::Ptr::*;
+
+///
+/// The register classes an instruction can address. The class
+/// decides how the register number is packed into its field.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    /// r0-r31, stored as is
+    Any,
+    /// r16-r31, stored as the offset from r16
+    High,
+    /// r16-r23, stored as the offset from r16
+    Mul,
+    /// Even registers, stored as the pair number
+    Pair,
+    /// r24, r26, r28 or r30, stored as the pair offset from r24
+    Word,
+}
+
+///
+/// The pointer register forms used by the indirect
+/// load and store instructions
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ptr {
+    X,
+    XInc,
+    XDec,
+    Y,
+    YInc,
+    YDec,
+    Z,
+    ZInc,
+    ZDec,
+}
+
+///
+/// The kinds of operands an instruction can take. Each kind
+/// owns one field letter in the instruction layout.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Destination register, field `d`
+    Rd(Class),
+    /// Source register, field `r`
+    Rr(Class),
+    /// Register used as both source and destination, fields `d` and `r`
+    Rdr,
+    /// 8 bit immediate, field `K`
+    K8,
+    /// 8 bit immediate stored complemented, field `K`
+    K8Inv,
+    /// 6 bit immediate, field `K`
+    K6,
+    /// 16 bit data space address, field `k`
+    K16,
+    /// Data space address of the reduced core's one word lds and
+    /// sts, 0x40 to 0xbf, field `k`
+    K7,
+    /// 7 bit relative branch target, field `k`
+    K7Rel,
+    /// 12 bit relative jump target, field `k`
+    K12Rel,
+    /// 22 bit absolute program address, field `k`
+    K22,
+    /// 5 bit I/O address, field `A`
+    A5,
+    /// 6 bit I/O address, field `A`
+    A6,
+    /// Bit number in a register or I/O location, field `b`
+    B,
+    /// Bit number in SREG, field `s`
+    S,
+    /// Pointer register, encoded in the fixed bits of the layout
+    P(Ptr),
+    /// Pointer register with a displacement, field `q`
+    Pq(Ptr),
+}
+
+///
+/// Describes one instruction form. The layout spells out every
+/// bit of the opcode, most significant first, using `0` and `1`
+/// for fixed bits and the field letters of the operand kinds for
+/// the rest. Whitespace in the layout is ignored.
+///
 #[derive(Debug)]
 pub struct Instruction {
-    index: u16,
-    opcode: u32,
+    pub name: &'static str,
+    pub operands: &'static [Kind],
+    pub layout: &'static str,
 }
 
-#[derive(Debug)]
-enum ObjectCode {
+///
+/// The assembled form of one line. Long instructions hold the
+/// first opcode word in the upper 16 bits.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectCode {
     Short(u16),
     Long(u32),
+    /// Bytes emitted by a data directive, padded to a whole word
+    Data(Vec<u8>),
 }
 
 ///
-/// Length returns the length of the opcode for the
-/// provided instruction code. This function does not
-/// produce any kind of error if an invalid instruction is
-/// provided.
+/// One parsed operand of an instruction
 ///
-pub fn length(code: &str) -> u32 {
-    match code {
-        "call" | "jmp" | "lds" => 32,
-        _ => 16,
-    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    /// General purpose register number
+    Reg(u32),
+    /// Constant, address or I/O location, as evaluated
+    Value(i64),
+    /// Pointer register in one of its addressing modes
+    Ptr(Ptr),
+    /// Pointer register with a displacement, as in `Y+q`
+    Disp(Ptr, u32),
+}
+
+macro_rules! ins {
+    ($name:expr, $layout:expr) => {
+        Instruction { name: $name, operands: &[], layout: $layout }
+    };
+    ($name:expr, $layout:expr, $($kind:expr),+) => {
+        Instruction { name: $name, operands: &[$($kind),+], layout: $layout }
+    };
 }
 
 ///
-/// Parses one instruction and returns the operands.
-/// This function will translate register symbols as
-/// well as symbols from the SYMTAB. This function assumes
-/// that the input string will be in the format [instruction] [operands]...
+/// Every instruction form known to the assembler. Instruction
+/// list borrowed from `avra`, layouts from the AVR Instruction
+/// Set Manual.
 ///
-pub fn get_operands(line: String, interm: &Interm) -> Result<Vec<u32>, String> {
-    let tokens = util::split_string(&line);
-    let mut ret = Vec::new();
+pub static INSTRUCTIONS: &[Instruction] = &[
+    ins!("nop",    "0000 0000 0000 0000"),
+    ins!("sec",    "1001 0100 0000 1000"),
+    ins!("clc",    "1001 0100 1000 1000"),
+    ins!("sen",    "1001 0100 0010 1000"),
+    ins!("cln",    "1001 0100 1010 1000"),
+    ins!("sez",    "1001 0100 0001 1000"),
+    ins!("clz",    "1001 0100 1001 1000"),
+    ins!("sei",    "1001 0100 0111 1000"),
+    ins!("cli",    "1001 0100 1111 1000"),
+    ins!("ses",    "1001 0100 0100 1000"),
+    ins!("cls",    "1001 0100 1100 1000"),
+    ins!("sev",    "1001 0100 0011 1000"),
+    ins!("clv",    "1001 0100 1011 1000"),
+    ins!("set",    "1001 0100 0110 1000"),
+    ins!("clt",    "1001 0100 1110 1000"),
+    ins!("seh",    "1001 0100 0101 1000"),
+    ins!("clh",    "1001 0100 1101 1000"),
+    ins!("sleep",  "1001 0101 1000 1000"),
+    ins!("wdr",    "1001 0101 1010 1000"),
+    ins!("ijmp",   "1001 0100 0000 1001"),
+    ins!("eijmp",  "1001 0100 0001 1001"),
+    ins!("icall",  "1001 0101 0000 1001"),
+    ins!("eicall", "1001 0101 0001 1001"),
+    ins!("ret",    "1001 0101 0000 1000"),
+    ins!("reti",   "1001 0101 0001 1000"),
+    ins!("spm",    "1001 0101 1110 1000"),
+    ins!("espm",   "1001 0101 1111 1000"),
+    ins!("break",  "1001 0101 1001 1000"),
+    ins!("lpm",    "1001 0101 1100 1000"),
+    ins!("elpm",   "1001 0101 1101 1000"),
+    ins!("bset",   "1001 0100 0sss 1000", S),
+    ins!("bclr",   "1001 0100 1sss 1000", S),
+    ins!("ser",    "1110 1111 dddd 1111", Rd(High)),
+    ins!("com",    "1001 010d dddd 0000", Rd(Any)),
+    ins!("neg",    "1001 010d dddd 0001", Rd(Any)),
+    ins!("inc",    "1001 010d dddd 0011", Rd(Any)),
+    ins!("dec",    "1001 010d dddd 1010", Rd(Any)),
+    ins!("lsr",    "1001 010d dddd 0110", Rd(Any)),
+    ins!("ror",    "1001 010d dddd 0111", Rd(Any)),
+    ins!("asr",    "1001 010d dddd 0101", Rd(Any)),
+    ins!("swap",   "1001 010d dddd 0010", Rd(Any)),
+    ins!("push",   "1001 001r rrrr 1111", Rr(Any)),
+    ins!("pop",    "1001 000d dddd 1111", Rd(Any)),
+    ins!("tst",    "0010 00rd dddd rrrr", Rdr),
+    ins!("clr",    "0010 01rd dddd rrrr", Rdr),
+    ins!("lsl",    "0000 11rd dddd rrrr", Rdr),
+    ins!("rol",    "0001 11rd dddd rrrr", Rdr),
+    ins!("breq",   "1111 00kk kkkk k001", K7Rel),
+    ins!("brne",   "1111 01kk kkkk k001", K7Rel),
+    ins!("brcs",   "1111 00kk kkkk k000", K7Rel),
+    ins!("brcc",   "1111 01kk kkkk k000", K7Rel),
+    ins!("brsh",   "1111 01kk kkkk k000", K7Rel),
+    ins!("brlo",   "1111 00kk kkkk k000", K7Rel),
+    ins!("brmi",   "1111 00kk kkkk k010", K7Rel),
+    ins!("brpl",   "1111 01kk kkkk k010", K7Rel),
+    ins!("brge",   "1111 01kk kkkk k100", K7Rel),
+    ins!("brlt",   "1111 00kk kkkk k100", K7Rel),
+    ins!("brhs",   "1111 00kk kkkk k101", K7Rel),
+    ins!("brhc",   "1111 01kk kkkk k101", K7Rel),
+    ins!("brts",   "1111 00kk kkkk k110", K7Rel),
+    ins!("brtc",   "1111 01kk kkkk k110", K7Rel),
+    ins!("brvs",   "1111 00kk kkkk k011", K7Rel),
+    ins!("brvc",   "1111 01kk kkkk k011", K7Rel),
+    ins!("brie",   "1111 00kk kkkk k111", K7Rel),
+    ins!("brid",   "1111 01kk kkkk k111", K7Rel),
+    ins!("rjmp",   "1100 kkkk kkkk kkkk", K12Rel),
+    ins!("rcall",  "1101 kkkk kkkk kkkk", K12Rel),
+    ins!("jmp",    "1001 010k kkkk 110k kkkk kkkk kkkk kkkk", K22),
+    ins!("call",   "1001 010k kkkk 111k kkkk kkkk kkkk kkkk", K22),
+    ins!("brbs",   "1111 00kk kkkk ksss", S, K7Rel),
+    ins!("brbc",   "1111 01kk kkkk ksss", S, K7Rel),
+    ins!("add",    "0000 11rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("adc",    "0001 11rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("sub",    "0001 10rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("sbc",    "0000 10rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("and",    "0010 00rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("or",     "0010 10rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("eor",    "0010 01rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("cp",     "0001 01rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("cpc",    "0000 01rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("cpse",   "0001 00rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("mov",    "0010 11rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("mul",    "1001 11rd dddd rrrr", Rd(Any), Rr(Any)),
+    ins!("movw",   "0000 0001 dddd rrrr", Rd(Pair), Rr(Pair)),
+    ins!("muls",   "0000 0010 dddd rrrr", Rd(High), Rr(High)),
+    ins!("mulsu",  "0000 0011 0ddd 0rrr", Rd(Mul), Rr(Mul)),
+    ins!("fmul",   "0000 0011 0ddd 1rrr", Rd(Mul), Rr(Mul)),
+    ins!("fmuls",  "0000 0011 1ddd 0rrr", Rd(Mul), Rr(Mul)),
+    ins!("fmulsu", "0000 0011 1ddd 1rrr", Rd(Mul), Rr(Mul)),
+    ins!("adiw",   "1001 0110 KKdd KKKK", Rd(Word), K6),
+    ins!("sbiw",   "1001 0111 KKdd KKKK", Rd(Word), K6),
+    ins!("subi",   "0101 KKKK dddd KKKK", Rd(High), K8),
+    ins!("sbci",   "0100 KKKK dddd KKKK", Rd(High), K8),
+    ins!("andi",   "0111 KKKK dddd KKKK", Rd(High), K8),
+    ins!("ori",    "0110 KKKK dddd KKKK", Rd(High), K8),
+    ins!("sbr",    "0110 KKKK dddd KKKK", Rd(High), K8),
+    ins!("cpi",    "0011 KKKK dddd KKKK", Rd(High), K8),
+    ins!("ldi",    "1110 KKKK dddd KKKK", Rd(High), K8),
+    ins!("cbr",    "0111 KKKK dddd KKKK", Rd(High), K8Inv),
+    ins!("sbrc",   "1111 110r rrrr 0bbb", Rr(Any), B),
+    ins!("sbrs",   "1111 111r rrrr 0bbb", Rr(Any), B),
+    ins!("bst",    "1111 101d dddd 0bbb", Rd(Any), B),
+    ins!("bld",    "1111 100d dddd 0bbb", Rd(Any), B),
+    ins!("in",     "1011 0AAd dddd AAAA", Rd(Any), A6),
+    ins!("out",    "1011 1AAr rrrr AAAA", A6, Rr(Any)),
+    ins!("sbic",   "1001 1001 AAAA Abbb", A5, B),
+    ins!("sbis",   "1001 1011 AAAA Abbb", A5, B),
+    ins!("sbi",    "1001 1010 AAAA Abbb", A5, B),
+    ins!("cbi",    "1001 1000 AAAA Abbb", A5, B),
+    ins!("lds",    "1001 000d dddd 0000 kkkk kkkk kkkk kkkk", Rd(Any), K16),
+    ins!("sts",    "1001 001r rrrr 0000 kkkk kkkk kkkk kkkk", K16, Rr(Any)),
+    ins!("lds",    "1010 0kkk dddd kkkk", Rd(High), K7),
+    ins!("sts",    "1010 1kkk rrrr kkkk", K7, Rr(High)),
+    ins!("ld",     "1001 000d dddd 1100", Rd(Any), P(X)),
+    ins!("ld",     "1001 000d dddd 1101", Rd(Any), P(XInc)),
+    ins!("ld",     "1001 000d dddd 1110", Rd(Any), P(XDec)),
+    ins!("ld",     "1000 000d dddd 1000", Rd(Any), P(Y)),
+    ins!("ld",     "1001 000d dddd 1001", Rd(Any), P(YInc)),
+    ins!("ld",     "1001 000d dddd 1010", Rd(Any), P(YDec)),
+    ins!("ld",     "1000 000d dddd 0000", Rd(Any), P(Z)),
+    ins!("ld",     "1001 000d dddd 0001", Rd(Any), P(ZInc)),
+    ins!("ld",     "1001 000d dddd 0010", Rd(Any), P(ZDec)),
+    ins!("st",     "1001 001r rrrr 1100", P(X), Rr(Any)),
+    ins!("st",     "1001 001r rrrr 1101", P(XInc), Rr(Any)),
+    ins!("st",     "1001 001r rrrr 1110", P(XDec), Rr(Any)),
+    ins!("st",     "1000 001r rrrr 1000", P(Y), Rr(Any)),
+    ins!("st",     "1001 001r rrrr 1001", P(YInc), Rr(Any)),
+    ins!("st",     "1001 001r rrrr 1010", P(YDec), Rr(Any)),
+    ins!("st",     "1000 001r rrrr 0000", P(Z), Rr(Any)),
+    ins!("st",     "1001 001r rrrr 0001", P(ZInc), Rr(Any)),
+    ins!("st",     "1001 001r rrrr 0010", P(ZDec), Rr(Any)),
+    ins!("ldd",    "10q0 qq0d dddd 1qqq", Rd(Any), Pq(Y)),
+    ins!("ldd",    "10q0 qq0d dddd 0qqq", Rd(Any), Pq(Z)),
+    ins!("std",    "10q0 qq1r rrrr 1qqq", Pq(Y), Rr(Any)),
+    ins!("std",    "10q0 qq1r rrrr 0qqq", Pq(Z), Rr(Any)),
+    ins!("lpm",    "1001 000d dddd 0100", Rd(Any), P(Z)),
+    ins!("lpm",    "1001 000d dddd 0101", Rd(Any), P(ZInc)),
+    ins!("elpm",   "1001 000d dddd 0110", Rd(Any), P(Z)),
+    ins!("elpm",   "1001 000d dddd 0111", Rd(Any), P(ZInc)),
+    ins!("xch",    "1001 001r rrrr 0100", P(Z), Rr(Any)),
+    ins!("las",    "1001 001r rrrr 0101", P(Z), Rr(Any)),
+    ins!("lac",    "1001 001r rrrr 0110", P(Z), Rr(Any)),
+    ins!("lat",    "1001 001r rrrr 0111", P(Z), Rr(Any)),
+];
 
-    for token in &tokens[1..] {
-        if interm.instructions.contains_key(token) {
-            continue;
-        }
+impl Instruction {
+    ///
+    /// Returns the operand signature of the instruction in the
+    /// notation of the instruction set manual, e.g. `ldi Rd, K`
+    ///
+    pub fn signature(&self) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|kind| kind.notation()).collect();
 
-        let mut token = token.to_string();
+        if operands.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, operands.join(", "))
+        }
+    }
 
-        if token.ends_with(",") {
-            token.pop();
+    ///
+    /// Validates the number and kinds of the provided operands
+    /// against the instruction signature
+    ///
+    pub fn check(&self, operands: &[Operand]) -> Result<(), String> {
+        if operands.len() != self.operands.len() {
+            return Err(format!(
+                "wrong number of operands for \"{}\", found {} (expected \"{}\")",
+                self.name,
+                operands.len(),
+                self.signature()
+            ));
         }
 
+        for (i, (kind, op)) in self.operands.iter().zip(operands).enumerate() {
+            let valid = match (*kind, *op) {
+                (Rd(_), Operand::Reg(_)) | (Rr(_), Operand::Reg(_)) | (Rdr, Operand::Reg(_)) => true,
+                (P(ptr), Operand::Ptr(p)) | (Pq(ptr), Operand::Disp(p, _)) => ptr == p,
+                (Rd(_), _) | (Rr(_), _) | (Rdr, _) | (P(_), _) | (Pq(_), _) => false,
+                (_, Operand::Value(_)) => true,
+                (_, _) => false,
+            };
 
-        match token.chars().next().unwrap() {
-            'r' => {
-                let result = reg_to_num(token);
+            if !valid {
+                return Err(format!(
+                    "operand {} of \"{}\" must be {}, found {} (expected \"{}\")",
+                    i + 1,
+                    self.name,
+                    kind.describe(),
+                    op.describe(),
+                    self.signature()
+                ));
+            }
 
-                match result {
-                    Ok(n) => ret.push(n),
-                    Err(e) => return Err(e)
+            match (*kind, *op) {
+                (_, Operand::Value(value)) => {
+                    if let Some((min, max)) = kind.range() {
+                        if value < min || value > max {
+                            return Err(format!(
+                                "operand {} of \"{}\" out of range ({}), must be {}..{}",
+                                i + 1,
+                                self.name,
+                                value,
+                                min,
+                                max
+                            ));
+                        }
+                    }
                 }
+                (Rd(class), Operand::Reg(n)) | (Rr(class), Operand::Reg(n)) if !class.contains(n) => {
+                    return Err(format!(
+                        "operand {} of \"{}\" must be {}, found r{}",
+                        i + 1,
+                        self.name,
+                        class.describe(),
+                        n
+                    ));
+                }
+                _ => {}
             }
+        }
 
-            '0'...'9' => {
-                match util::num_from_str(token) {
-                    Ok(n) => ret.push(n),
-                    Err(e) => return Err(e)
-                }
-            },
+        Ok(())
+    }
+
+    ///
+    /// Returns the number of 16 bit words the instruction
+    /// occupies in program memory
+    ///
+    pub fn words(&self) -> u32 {
+        self.bits().count() as u32 / 16
+    }
 
-            ';' => return Ok(ret),
+    fn bits<'a>(&'a self) -> impl Iterator<Item = char> + 'a {
+        self.layout.chars().filter(|c| !c.is_whitespace())
+    }
 
-            _ => {
-                match interm.symtab.get(token.as_str()) {
-                    Some(&n) => ret.push(n),
-                    None => return Err(format!("Undefined symbol {}", token))
-                }
+    ///
+    /// Places `value` into every bit of the layout marked with
+    /// `field`, least significant bit first
+    ///
+    fn place(&self, field: char, value: u32) -> u32 {
+        let mut value = value;
+        let mut ret = 0;
+
+        for (i, c) in self.bits().collect::<Vec<char>>().iter().rev().enumerate() {
+            if *c == field {
+                ret |= (value & 1) << i;
+                value >>= 1;
             }
         }
+
+        ret
     }
 
-    Ok(ret)
+    ///
+    /// Combines the fixed bits of the layout with the
+    /// provided operand values. `addr` is the word address of
+    /// the instruction and is used to resolve relative targets.
+    ///
+    pub fn encode(&self, operands: &[Operand], addr: u32) -> Result<u32, String> {
+        let mut code = self.place('1', u32::MAX);
+
+        for (i, kind) in self.operands.iter().enumerate() {
+            let op = match operands.get(i) {
+                Some(op) => *op,
+                None => return Err(format!("Missing operand {}", i + 1)),
+            };
+            let n = op.value();
+
+            code |= match *kind {
+                Rd(class) => self.place('d', class.encode(n)),
+                Rr(class) => self.place('r', class.encode(n)),
+                Rdr => self.place('d', n) | self.place('r', n),
+                K8 | K6 => self.place('K', n),
+                K8Inv => self.place('K', !n),
+                K16 | K22 => self.place('k', n),
+                // Bit 6 of the address comes before bits 4 and 5,
+                // bit 7 is its complement and is not stored
+                K7 => self.place('k', (n & 0xf) | (n >> 6 & 1) << 4 | (n >> 4 & 3) << 5),
+                K7Rel | K12Rel => self.place('k', self.offset(*kind, op.target(), addr)? as u32),
+                A5 | A6 => self.place('A', n),
+                B => self.place('b', n),
+                S => self.place('s', n),
+                P(_) => 0,
+                Pq(_) => self.place('q', n),
+            };
+        }
+
+        Ok(code)
+    }
+
+    ///
+    /// Computes the word distance from the instruction following
+    /// `addr` to `target` and makes sure it fits the field of a
+    /// relative branch or jump
+    ///
+    fn offset(&self, kind: Kind, target: i64, addr: u32) -> Result<i64, String> {
+        let offset = target - i64::from(addr) - 1;
+        let bits = if kind == K7Rel { 7 } else { 12 };
+        let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+
+        if offset >= min && offset <= max {
+            return Ok(offset);
+        }
+
+        let error = format!(
+            "target of \"{}\" out of range: distance is {} words, limit is {}..+{}",
+            self.name, offset, min, max
+        );
+
+        // Whether jmp or call can replace rjmp or rcall depends on
+        // the device, so the second pass gives that hint
+        match (self.name, inverse(self.name)) {
+            ("rjmp", _) | ("rcall", _) => Err(error),
+            (_, Some(inv)) => Err(format!("{} (use \"{}\" to skip over a \"rjmp\" or \"jmp\" to the target)", error, inv)),
+            (_, None) => Err(format!("{} (branch over a \"rjmp\" or \"jmp\" to the target)", error)),
+        }
+    }
 }
 
-///
-/// Takes a string in the form r[d][d] and returns the integer
-/// representation
-///
-pub fn reg_to_num(reg: String) -> Result<u32, String> {
-    match reg[1..].parse::<u32>() {
-        Ok(n) => {
-            match n {
-                0...31 => {
-                    return Ok(n);
-                }
-                _ => {
-                    return Err(format!("Register number out of range ({})", n));
-                }
-            }
+impl Class {
+    ///
+    /// Checks whether register `n` belongs to the class
+    ///
+    fn contains(self, n: u32) -> bool {
+        match self {
+            Any => n <= 31,
+            High => (16..=31).contains(&n),
+            Mul => (16..=23).contains(&n),
+            Pair => n <= 31 && n & 1 == 0,
+            Word => n == 24 || n == 26 || n == 28 || n == 30,
+        }
+    }
+
+    ///
+    /// Describes the registers in the class for error messages
+    ///
+    fn describe(self) -> &'static str {
+        match self {
+            Any => "r0-r31",
+            High => "r16-r31",
+            Mul => "r16-r23",
+            Pair => "an even register",
+            Word => "r24, r26, r28 or r30",
+        }
+    }
+
+    ///
+    /// Converts a register number into the value stored in
+    /// the register field
+    ///
+    fn encode(self, n: u32) -> u32 {
+        match self {
+            Any => n,
+            High | Mul => n.wrapping_sub(16),
+            Pair => n >> 1,
+            Word => n.wrapping_sub(24) >> 1,
         }
-        Err(e) => return Err(format!("Failed to parse register number: {}", e))
     }
 }
 
 ///
-/// Assembles one instruction and returns the
-/// binary representation
+/// Returns the conditional branch testing the opposite condition
 ///
-fn parse(ins: Instruction) -> ObjectCode {
-    match ins.index {
-        0...27 => {
-            println!("<= break");
-        }
+fn inverse(code: &str) -> Option<&'static str> {
+    let pairs = [
+        ("breq", "brne"),
+        ("brcs", "brcc"),
+        ("brsh", "brlo"),
+        ("brmi", "brpl"),
+        ("brge", "brlt"),
+        ("brhs", "brhc"),
+        ("brts", "brtc"),
+        ("brvs", "brvc"),
+        ("brie", "brid"),
+        ("brbs", "brbc"),
+    ];
 
-        28 | 29 => {
-            println!("<= elpm");
+    pairs.iter().find_map(|&(a, b)| match code {
+        c if c == a => Some(b),
+        c if c == b => Some(a),
+        _ => None,
+    })
+}
+
+impl Ptr {
+    ///
+    /// Returns the assembly syntax of the addressing mode
+    ///
+    pub fn name(self) -> &'static str {
+        match self {
+            X => "X",
+            XInc => "X+",
+            XDec => "-X",
+            Y => "Y",
+            YInc => "Y+",
+            YDec => "-Y",
+            Z => "Z",
+            ZInc => "Z+",
+            ZDec => "-Z",
         }
+    }
 
-        index => {
-            // check for operand 1 and 2 here
+    ///
+    /// Parses a pointer register token such as `X`, `Y+`, `-Z` or
+    /// `Z+5`. Returns the addressing mode and, for the displacement
+    /// form, the text of the displacement.
+    ///
+    pub fn from_token(token: &str) -> Option<(Ptr, Option<&str>)> {
+        let ptr = match token.to_uppercase().as_str() {
+            "X" => X,
+            "X+" => XInc,
+            "-X" => XDec,
+            "Y" => Y,
+            "Y+" => YInc,
+            "-Y" => YDec,
+            "Z" => Z,
+            "Z+" => ZInc,
+            "-Z" => ZDec,
+            _ => {
+                let bytes = token.as_bytes();
+
+                // Comparing bytes keeps a multibyte first character
+                // from splitting the token inside a character
+                if bytes.len() < 3 || bytes[1] != b'+' {
+                    return None;
+                }
 
-            if index >= 69 {
-                println!(">= brbs");
+                let ptr = match bytes[0] {
+                    b'X' | b'x' => X,
+                    b'Y' | b'y' => Y,
+                    b'Z' | b'z' => Z,
+                    _ => return None,
+                };
+
+                return Some((ptr, Some(&token[2..])));
             }
+        };
 
-            match index {
-                0...31 => {
-                    println!("<= bclr");
-                }
+        Some((ptr, None))
+    }
+}
 
-                32...46 => {
-                    println!("<= rol");
-                }
+impl Kind {
+    ///
+    /// Returns the name used for the operand in the
+    /// instruction set manual
+    ///
+    fn notation(self) -> String {
+        match self {
+            Rd(_) | Rdr => String::from("Rd"),
+            Rr(_) => String::from("Rr"),
+            K8 | K8Inv | K6 => String::from("K"),
+            K16 | K7 | K7Rel | K12Rel | K22 => String::from("k"),
+            A5 | A6 => String::from("A"),
+            B => String::from("b"),
+            S => String::from("s"),
+            P(ptr) => ptr.name().to_string(),
+            Pq(ptr) => format!("{}+q", ptr.name()),
+        }
+    }
 
-                47...66 => {
-                    println!("<= rcall");
-                }
+    ///
+    /// Returns the values that fit the field of a constant
+    /// operand. 8 bit constants may also be negative, in which
+    /// case they are stored in two's complement.
+    ///
+    fn range(self) -> Option<(i64, i64)> {
+        match self {
+            K8 | K8Inv => Some((-128, 255)),
+            K6 | A6 => Some((0, 63)),
+            A5 => Some((0, 31)),
+            B | S => Some((0, 7)),
+            K16 => Some((0, 0xffff)),
+            K7 => Some((0x40, 0xbf)),
+            K22 => Some((0, 0x3f_ffff)),
+            _ => None,
+        }
+    }
 
-                67 | 68  => {
-                    println!("<= call");
-                }
+    ///
+    /// Describes the operand kind for error messages
+    ///
+    fn describe(self) -> String {
+        match self {
+            Rd(_) | Rr(_) | Rdr => String::from("a register"),
+            K8 | K8Inv | K6 => String::from("a constant"),
+            K16 | K7 => String::from("a data address"),
+            K7Rel | K12Rel | K22 => String::from("a program address"),
+            A5 | A6 => String::from("an I/O address"),
+            B | S => String::from("a bit number"),
+            P(ptr) => format!("pointer {}", ptr.name()),
+            Pq(ptr) => format!("pointer {}+q", ptr.name()),
+        }
+    }
 
-                69 | 70 => {
-                    println!("<= brbc");
-                }
+    ///
+    /// Checks whether an operand token has the shape this kind
+    /// expects. Only pointer operands can be told apart by shape,
+    /// everything else is checked once the value is known.
+    ///
+    fn accepts(self, token: &str) -> bool {
+        let token: String = token.split_whitespace().collect();
 
-                71...82 => {
-                    println!("<= mul");
-                }
+        match (self, Ptr::from_token(&token)) {
+            (P(ptr), Some((p, None))) | (Pq(ptr), Some((p, Some(_)))) => ptr == p,
+            (P(_), _) | (Pq(_), _) => false,
+            (_, ptr) => ptr.is_none(),
+        }
+    }
+}
 
-                83 => {
-                    println!("<= movw");
-                }
+impl Operand {
+    ///
+    /// Describes the operand for error messages
+    ///
+    fn describe(self) -> String {
+        match self {
+            Operand::Reg(n) => format!("register r{}", n),
+            Operand::Value(n) => format!("value {}", n),
+            Operand::Ptr(ptr) => format!("pointer {}", ptr.name()),
+            Operand::Disp(ptr, q) => format!("pointer {}+{}", ptr.name(), q),
+        }
+    }
 
-                84 => {
-                    println!("<= muls");
-                }
+    ///
+    /// Returns the number stored in the instruction field
+    /// for this operand. Values are narrowed to two's complement,
+    /// so they must have passed the range check of their kind.
+    ///
+    pub fn value(self) -> u32 {
+        match self {
+            Operand::Reg(n) | Operand::Disp(_, n) => n,
+            Operand::Value(n) => n as u32,
+            Operand::Ptr(_) => 0,
+        }
+    }
 
-                85...88 => {
-                    println!("<= fmulsu");
-                }
+    ///
+    /// Returns the address a relative branch or jump goes to
+    ///
+    pub fn target(self) -> i64 {
+        match self {
+            Operand::Value(n) => n,
+            _ => i64::from(self.value()),
+        }
+    }
+}
 
-                89 | 90 => {
-                    println!("<= sbiw");
-                }
+///
+/// Picks the form of an instruction whose operand kinds match
+/// the shape of the provided operand tokens. Instructions such
+/// as `ld` or `lpm` have one form per pointer addressing mode.
+///
+pub fn select(
+    interm: &Interm,
+    code: &str,
+    operands: &[&str],
+) -> Result<&'static Instruction, String> {
+    let forms = match interm.instructions.get(code) {
+        Some(forms) => forms,
+        None => return Err(format!("unknown instruction \"{}\"", code)),
+    };
 
-                91...98 => {
-                    println!("<= cbr");
-                }
+    let mut matching = forms.iter().cloned().filter(|ins| {
+        ins.operands.len() == operands.len()
+            && ins.operands.iter().zip(operands).all(|(kind, token)| kind.accepts(token))
+    });
 
-                99...102 => {
-                    println!("<= bld");
-                }
+    // Forms of the same shape, such as the two lds, are told
+    // apart by the device. Unsupported ones are left for the
+    // device check to report.
+    if let Some(first) = matching.next() {
+        let supported = |ins: &Instruction| interm.device.as_ref().map(|d| d.supports(ins)).unwrap_or(true);
 
-                103 => {
-                    println!("in");
-                }
+        if supported(first) {
+            return Ok(first);
+        }
 
-                104 => {
-                    println!("out");
-                }
+        return Ok(matching.find(|ins| supported(ins)).unwrap_or(first));
+    }
 
-                105...108 => {
-                    println!("<= cbi");
-                }
+    let expected: Vec<String> = forms.iter().map(|ins| format!("\"{}\"", ins.signature())).collect();
 
-                109 => {
-                    println!("lds");
-                }
+    if forms.len() > 1 {
+        Err(format!(
+            "invalid operands for \"{}\" (expected one of {})",
+            code,
+            expected.join(", ")
+        ))
+    } else if forms[0].operands.len() != operands.len() {
+        Err(format!(
+            "wrong number of operands for \"{}\", found {} (expected {})",
+            code,
+            operands.len(),
+            expected[0]
+        ))
+    } else {
+        Err(format!("invalid operands for \"{}\" (expected {})", code, expected[0]))
+    }
+}
 
-                110 => {
-                    println!("sts");
-                }
+///
+/// Parses one instruction and returns the operands.
+/// This function will translate register symbols as
+/// well as evaluate expressions over the SYMTAB. This function
+/// assumes that the input string will be in the format
+/// [label:] [instruction] [operands]...
+///
+pub fn get_operands(line: String, interm: &Interm) -> Result<Vec<Operand>, String> {
+    let (_, _, operands) = util::split_statement(&line);
+    let mut ret = Vec::new();
 
-                111 => {
-                    println!("ld");
-                }
+    for operand in operands {
+        let token: String = operand.split_whitespace().collect();
 
-                112 => {
-                    println!("st");
+        if let Some((ptr, disp)) = Ptr::from_token(&token) {
+            match disp {
+                None => ret.push(Operand::Ptr(ptr)),
+                Some(_) if ptr == X => {
+                    return Err(format!("Pointer X does not support displacement ({})", token));
                 }
+                Some(q) => {
+                    let q = get_value(q, interm)?;
 
-                113 => {
-                    println!("ldd");
-                }
+                    if !(0..=63).contains(&q) {
+                        return Err(format!("Displacement out of range ({}), must be 0-63", q));
+                    }
 
-                114 => {
-                    println!("std");
+                    ret.push(Operand::Disp(ptr, q as u32));
                 }
+            }
+        } else if let Some(reg) = get_register(&token, interm) {
+            ret.push(Operand::Reg(reg?));
+        } else {
+            ret.push(Operand::Value(get_value(operand, interm)?));
+        }
+    }
 
-                i => {
-                    println!("else {}", i);
-                }
+    Ok(ret)
+}
 
-            }
+///
+/// Evaluates an operand expression over the SYMTAB. The value
+/// is kept whole so that the range check sees it unchanged.
+///
+fn get_value(text: &str, interm: &Interm) -> Result<i64, String> {
+    expr::eval(text, &interm.symtab)
+}
 
-        }
+///
+/// Translates a register in the form r[d][d] or a register
+/// alias into the register number. Returns `None` when the
+/// token does not name a register.
+///
+pub fn get_register(token: &str, interm: &Interm) -> Option<Result<u32, String>> {
+    if (token.starts_with('r') || token.starts_with('R'))
+        && token.len() > 1
+        && token[1..].chars().all(|c| c.is_ascii_digit())
+    {
+        return Some(reg_to_num(token.to_string()));
+    }
+
+    interm.aliases.get(&token.to_lowercase()).map(|&n| Ok(n))
+}
 
+///
+/// Takes a string in the form r[d][d] and returns the integer
+/// representation
+///
+pub fn reg_to_num(reg: String) -> Result<u32, String> {
+    match reg[1..].parse::<u32>() {
+        Ok(n) => match n {
+            0..=31 => Ok(n),
+            _ => Err(format!("Register number out of range ({})", n)),
+        },
+        Err(e) => Err(format!("Failed to parse register number: {}", e)),
     }
+}
 
-    return ObjectCode::Short(0x200);
+///
+/// Assembles one instruction and returns the
+/// binary representation. `addr` is the word address
+/// of the instruction and is used to resolve relative
+/// branch targets.
+///
+pub fn parse(ins: &Instruction, operands: &[Operand], addr: u32) -> Result<ObjectCode, String> {
+    ins.check(operands)?;
+    let code = ins.encode(operands, addr)?;
+
+    match ins.words() {
+        1 => Ok(ObjectCode::Short(code as u16)),
+        _ => Ok(ObjectCode::Long(code)),
+    }
 }
 
 ///
 /// This function initializes the list of available instructions
-/// since we cannot (yet) have static HashMaps in Rust. Instruction
-/// list borrowed from `avra`
+/// since we cannot (yet) have static HashMaps in Rust.
 ///
 pub fn init_op_map(interm: &mut Interm) {
     interm.instructions = HashMap::new();
 
-    interm.instructions.insert("nop",   Instruction{opcode: 0x0000, index: 0});
-    interm.instructions.insert("sec",   Instruction{opcode: 0x9408, index: 1});
-    interm.instructions.insert("clc",   Instruction{opcode: 0x9488, index: 2});
-    interm.instructions.insert("sen",   Instruction{opcode: 0x9428, index: 3});
-    interm.instructions.insert("cln",   Instruction{opcode: 0x94a8, index: 4});
-    interm.instructions.insert("sez",   Instruction{opcode: 0x9418, index: 5});
-    interm.instructions.insert("clz",   Instruction{opcode: 0x9498, index: 6});
-    interm.instructions.insert("sei",   Instruction{opcode: 0x9478, index: 7});
-    interm.instructions.insert("cli",   Instruction{opcode: 0x94f8, index: 8});
-    interm.instructions.insert("ses",   Instruction{opcode: 0x9448, index: 9});
-    interm.instructions.insert("cls",   Instruction{opcode: 0x94c8, index: 10});
-    interm.instructions.insert("sev",   Instruction{opcode: 0x9438, index: 11});
-    interm.instructions.insert("clv",   Instruction{opcode: 0x94b8, index: 12});
-    interm.instructions.insert("set",   Instruction{opcode: 0x9468, index: 13});
-    interm.instructions.insert("clt",   Instruction{opcode: 0x94e8, index: 14});
-    interm.instructions.insert("seh",   Instruction{opcode: 0x9458, index: 15});
-    interm.instructions.insert("clh",   Instruction{opcode: 0x94d8, index: 16});
-    interm.instructions.insert("sleep", Instruction{opcode: 0x9588, index: 17});
-    interm.instructions.insert("wdr",   Instruction{opcode: 0x95a8, index: 18});
-    interm.instructions.insert("ijmp",  Instruction{opcode: 0x9409, index: 19});
-    interm.instructions.insert("eijmp", Instruction{opcode: 0x9419, index: 20});
-    interm.instructions.insert("icall", Instruction{opcode: 0x9509, index: 21});
-    interm.instructions.insert("eicall",Instruction{opcode: 0x9519, index: 22});
-    interm.instructions.insert("ret",   Instruction{opcode: 0x9508, index: 23});
-    interm.instructions.insert("reti",  Instruction{opcode: 0x9518, index: 24});
-    interm.instructions.insert("spm",   Instruction{opcode: 0x95e8, index: 25});
-    interm.instructions.insert("espm",  Instruction{opcode: 0x95f8, index: 26});
-    interm.instructions.insert("break", Instruction{opcode: 0x9598, index: 27});
-    interm.instructions.insert("lpm",   Instruction{opcode: 0x95c8, index: 28});
-    interm.instructions.insert("elpm",  Instruction{opcode: 0x95d8, index: 29});
-    interm.instructions.insert("bset",  Instruction{opcode: 0x9408, index: 30});
-    interm.instructions.insert("bclr",  Instruction{opcode: 0x9488, index: 31});
-    interm.instructions.insert("ser",   Instruction{opcode: 0xef0f, index: 32});
-    interm.instructions.insert("com",   Instruction{opcode: 0x9400, index: 33});
-    interm.instructions.insert("neg",   Instruction{opcode: 0x9401, index: 34});
-    interm.instructions.insert("inc",   Instruction{opcode: 0x9403, index: 35});
-    interm.instructions.insert("dec",   Instruction{opcode: 0x940a, index: 36});
-    interm.instructions.insert("lsr",   Instruction{opcode: 0x9406, index: 37});
-    interm.instructions.insert("ror",   Instruction{opcode: 0x9407, index: 38});
-    interm.instructions.insert("asr",   Instruction{opcode: 0x9405, index: 39});
-    interm.instructions.insert("swap",  Instruction{opcode: 0x9402, index: 40});
-    interm.instructions.insert("push",  Instruction{opcode: 0x920f, index: 41});
-    interm.instructions.insert("pop",   Instruction{opcode: 0x900f, index: 42});
-    interm.instructions.insert("tst",   Instruction{opcode: 0x2000, index: 43});
-    interm.instructions.insert("clr",   Instruction{opcode: 0x2400, index: 44});
-    interm.instructions.insert("lsl",   Instruction{opcode: 0x0c00, index: 45});
-    interm.instructions.insert("rol",   Instruction{opcode: 0x1c00, index: 46});
-    interm.instructions.insert("breq",  Instruction{opcode: 0xf001, index: 47});
-    interm.instructions.insert("brne",  Instruction{opcode: 0xf401, index: 48});
-    interm.instructions.insert("brcs",  Instruction{opcode: 0xf000, index: 49});
-    interm.instructions.insert("brcc",  Instruction{opcode: 0xf400, index: 50});
-    interm.instructions.insert("brsh",  Instruction{opcode: 0xf400, index: 51});
-    interm.instructions.insert("brlo",  Instruction{opcode: 0xf000, index: 52});
-    interm.instructions.insert("brmi",  Instruction{opcode: 0xf002, index: 53});
-    interm.instructions.insert("brpl",  Instruction{opcode: 0xf402, index: 54});
-    interm.instructions.insert("brge",  Instruction{opcode: 0xf404, index: 55});
-    interm.instructions.insert("brlt",  Instruction{opcode: 0xf004, index: 56});
-    interm.instructions.insert("brhs",  Instruction{opcode: 0xf005, index: 57});
-    interm.instructions.insert("brhc",  Instruction{opcode: 0xf405, index: 58});
-    interm.instructions.insert("brts",  Instruction{opcode: 0xf006, index: 59});
-    interm.instructions.insert("brtc",  Instruction{opcode: 0xf406, index: 60});
-    interm.instructions.insert("brvs",  Instruction{opcode: 0xf003, index: 61});
-    interm.instructions.insert("brvc",  Instruction{opcode: 0xf403, index: 62});
-    interm.instructions.insert("brie",  Instruction{opcode: 0xf007, index: 63});
-    interm.instructions.insert("brid",  Instruction{opcode: 0xf407, index: 64});
-    interm.instructions.insert("rjmp",  Instruction{opcode: 0xc000, index: 65});
-    interm.instructions.insert("rcall", Instruction{opcode: 0xd000, index: 66});
-    interm.instructions.insert("jmp",   Instruction{opcode: 0x940c, index: 67});
-    interm.instructions.insert("call",  Instruction{opcode: 0x940e, index: 68});
-    interm.instructions.insert("brbs",  Instruction{opcode: 0xf000, index: 69});
-    interm.instructions.insert("brbc",  Instruction{opcode: 0xf400, index: 70});
-    interm.instructions.insert("add",   Instruction{opcode: 0x0c00, index: 71});
-    interm.instructions.insert("adc",   Instruction{opcode: 0x1c00, index: 72});
-    interm.instructions.insert("sub",   Instruction{opcode: 0x1800, index: 73});
-    interm.instructions.insert("sbc",   Instruction{opcode: 0x0800, index: 74});
-    interm.instructions.insert("and",   Instruction{opcode: 0x2000, index: 75});
-    interm.instructions.insert("or",    Instruction{opcode: 0x2800, index: 76});
-    interm.instructions.insert("eor",   Instruction{opcode: 0x2400, index: 77});
-    interm.instructions.insert("cp",    Instruction{opcode: 0x1400, index: 78});
-    interm.instructions.insert("cpc",   Instruction{opcode: 0x0400, index: 79});
-    interm.instructions.insert("cpse",  Instruction{opcode: 0x1000, index: 80});
-    interm.instructions.insert("mov",   Instruction{opcode: 0x2c00, index: 81});
-    interm.instructions.insert("mul",   Instruction{opcode: 0x9c00, index: 82});
-    interm.instructions.insert("movw",  Instruction{opcode: 0x0100, index: 83});
-    interm.instructions.insert("muls",  Instruction{opcode: 0x0200, index: 84});
-    interm.instructions.insert("mulsu", Instruction{opcode: 0x0300, index: 85});
-    interm.instructions.insert("fmul",  Instruction{opcode: 0x0308, index: 86});
-    interm.instructions.insert("fmuls", Instruction{opcode: 0x0380, index: 87});
-    interm.instructions.insert("fmulsu",Instruction{opcode: 0x0388, index: 88});
-    interm.instructions.insert("adiw",  Instruction{opcode: 0x9600, index: 89});
-    interm.instructions.insert("sbiw",  Instruction{opcode: 0x9700, index: 90});
-    interm.instructions.insert("subi",  Instruction{opcode: 0x5000, index: 91});
-    interm.instructions.insert("sbci",  Instruction{opcode: 0x4000, index: 92});
-    interm.instructions.insert("andi",  Instruction{opcode: 0x7000, index: 93});
-    interm.instructions.insert("ori",   Instruction{opcode: 0x6000, index: 94});
-    interm.instructions.insert("sbr",   Instruction{opcode: 0x6000, index: 95});
-    interm.instructions.insert("cpi",   Instruction{opcode: 0x3000, index: 96});
-    interm.instructions.insert("ldi",   Instruction{opcode: 0xe000, index: 97});
-    interm.instructions.insert("cbr",   Instruction{opcode: 0x7000, index: 98});
-    interm.instructions.insert("sbrc",  Instruction{opcode: 0xfc00, index: 99});
-    interm.instructions.insert("sbrs",  Instruction{opcode: 0xfe00, index: 100});
-    interm.instructions.insert("bst",   Instruction{opcode: 0xfa00, index: 101});
-    interm.instructions.insert("bld",   Instruction{opcode: 0xf800, index: 102});
-    interm.instructions.insert("in",    Instruction{opcode: 0xb000, index: 103});
-    interm.instructions.insert("out",   Instruction{opcode: 0xb800, index: 104});
-    interm.instructions.insert("sbic",  Instruction{opcode: 0x9900, index: 105});
-    interm.instructions.insert("sbis",  Instruction{opcode: 0x9b00, index: 106});
-    interm.instructions.insert("sbi",   Instruction{opcode: 0x9a00, index: 107});
-    interm.instructions.insert("cbi",   Instruction{opcode: 0x9800, index: 108});
-    interm.instructions.insert("lds",   Instruction{opcode: 0x9000, index: 109});
-    interm.instructions.insert("sts",   Instruction{opcode: 0x9200, index: 110});
-    interm.instructions.insert("ld",    Instruction{opcode: 0,      index: 111});
-    interm.instructions.insert("st",    Instruction{opcode: 0,      index: 112});
-    interm.instructions.insert("ldd",   Instruction{opcode: 0,      index: 113});
-    interm.instructions.insert("std",   Instruction{opcode: 0,      index: 114});
-    interm.instructions.insert("count", Instruction{opcode: 0,      index: 115});
-    interm.instructions.insert("lpm",   Instruction{opcode: 0x9004, index: 116});
-    interm.instructions.insert("lpm",   Instruction{opcode: 0x9005, index: 117});
-    interm.instructions.insert("elpm",  Instruction{opcode: 0x9006, index: 118});
-    interm.instructions.insert("elpm",  Instruction{opcode: 0x9007, index: 119});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x900c, index: 120});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x900d, index: 121});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x900e, index: 122});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x8008, index: 123});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x9009, index: 124});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x900a, index: 125});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x8000, index: 126});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x9001, index: 127});
-    interm.instructions.insert("ld",    Instruction{opcode: 0x9002, index: 128});
-    interm.instructions.insert("st",    Instruction{opcode: 0x920c, index: 129});
-    interm.instructions.insert("st",    Instruction{opcode: 0x920d, index: 130});
-    interm.instructions.insert("st",    Instruction{opcode: 0x920e, index: 131});
-    interm.instructions.insert("st",    Instruction{opcode: 0x8208, index: 132});
-    interm.instructions.insert("st",    Instruction{opcode: 0x9209, index: 133});
-    interm.instructions.insert("st",    Instruction{opcode: 0x920a, index: 134});
-    interm.instructions.insert("st",    Instruction{opcode: 0x8200, index: 135});
-    interm.instructions.insert("st",    Instruction{opcode: 0x9201, index: 136});
-    interm.instructions.insert("st",    Instruction{opcode: 0x9202, index: 137});
-    interm.instructions.insert("ldd",   Instruction{opcode: 0x8008, index: 138});
-    interm.instructions.insert("ldd",   Instruction{opcode: 0x8000, index: 139});
-    interm.instructions.insert("std",   Instruction{opcode: 0x8208, index: 140});
-    interm.instructions.insert("std",   Instruction{opcode: 0x8200, index: 141});
-    interm.instructions.insert("end",   Instruction{opcode: 0,      index: 142});
+    for ins in INSTRUCTIONS {
+        interm.instructions.entry(ins.name).or_default().push(ins);
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use super::Operand::*;
 
     fn init_fake_interm() -> Interm {
-        let mut interm = Interm {
-            lines: Vec::new(),
-            optab: Vec::new(),
-            instructions: HashMap::new(),
-            locctr: 0,
-            linectr: 0,
-            symtab: HashMap::new(),
-        };
+        let mut interm = Interm::new();
 
         init_op_map(&mut interm);
         interm.symtab.insert(String::from("defined_label"), 200);
@@ -413,31 +884,207 @@ mod test {
 
     #[test]
     fn test_get_operands() {
-        let mut interm = init_fake_interm();
+        let interm = init_fake_interm();
 
-        assert_eq!(get_operands(String::from("ldi r16, 0xff"), &interm), Ok(vec![16, 0xff]));
-        assert_eq!(get_operands(String::from("test:       jmp 0x23"), &interm), Ok(vec![0x23]));
+        assert_eq!(get_operands(String::from("ldi r16, 0xff"), &interm), Ok(vec![Reg(16), Value(0xff)]));
+        assert_eq!(get_operands(String::from("test:       jmp 0x23"), &interm), Ok(vec![Value(0x23)]));
         assert_eq!(get_operands(String::from("loop: nop"), &interm), Ok(vec![]));
         assert_eq!(get_operands(String::from("brne done"), &interm), Err(String::from("Undefined symbol done")));
         assert_eq!(get_operands(String::from("nop"), &interm), Ok(vec![]));
         assert_eq!(get_operands(String::from("label:"), &interm), Ok(vec![]));
-        assert_eq!(get_operands(String::from("label: ldi r16, 0x22"), &interm), Ok(vec![16, 0x22]));
-        assert_eq!(get_operands(String::from("label: ldi r29, 0b10001010"), &interm), Ok(vec![29, 0b10001010]));
-        assert_eq!(get_operands(String::from("jmp defined_label"), &interm), Ok(vec![200]));
+        assert_eq!(get_operands(String::from("label: ldi r16, 0x22"), &interm), Ok(vec![Reg(16), Value(0x22)]));
+        assert_eq!(get_operands(String::from("label: ldi r29, 0b10001010"), &interm), Ok(vec![Reg(29), Value(0b10001010)]));
+        assert_eq!(get_operands(String::from("jmp defined_label"), &interm), Ok(vec![Value(200)]));
         assert_eq!(get_operands(String::from("jmp undefined"), &interm), Err(String::from("Undefined symbol undefined")));
-        assert_eq!(get_operands(String::from("label: lds r16, PORTB"), &interm), Ok(vec![16, 0xBEEF]));
-        assert_eq!(get_operands(String::from("label: out PORTL, r16"), &interm), Ok(vec![0xDEAD, 16]));
+        assert_eq!(get_operands(String::from("label: lds r16, PORTB"), &interm), Ok(vec![Reg(16), Value(0xBEEF)]));
+        assert_eq!(get_operands(String::from("label: out PORTL, r16"), &interm), Ok(vec![Value(0xDEAD), Reg(16)]));
+        assert_eq!(get_operands(String::from("ldi r16, (1 << 5) | (1 << 4)"), &interm), Ok(vec![Reg(16), Value(0x30)]));
+        assert_eq!(get_operands(String::from("out PORTB+1, r16 ; comment"), &interm), Ok(vec![Value(0xBEF0), Reg(16)]));
+        assert_eq!(get_operands(String::from("ldi r16, -1"), &interm), Ok(vec![Reg(16), Value(-1)]));
+        assert_eq!(get_operands(String::from("ldd r0, Y + 2 * 3"), &interm), Ok(vec![Reg(0), Disp(Y, 6)]));
+        assert_eq!(get_operands(String::from("ld r16, X+"), &interm), Ok(vec![Reg(16), Ptr(XInc)]));
+        assert_eq!(get_operands(String::from("st -Y, r0"), &interm), Ok(vec![Ptr(YDec), Reg(0)]));
+        assert_eq!(get_operands(String::from("ld r1, z"), &interm), Ok(vec![Reg(1), Ptr(Z)]));
+        assert_eq!(get_operands(String::from("ldd r24, Z+5"), &interm), Ok(vec![Reg(24), Disp(Z, 5)]));
+        assert_eq!(get_operands(String::from("std Y+63, r2"), &interm), Ok(vec![Disp(Y, 63), Reg(2)]));
+        assert_eq!(get_operands(String::from("ldd r24, Y+64"), &interm),
+                   Err(String::from("Displacement out of range (64), must be 0-63")));
+        assert_eq!(get_operands(String::from("ldd r24, X+1"), &interm),
+                   Err(String::from("Pointer X does not support displacement (X+1)")));
+    }
+
+    #[test]
+    fn test_words() {
+        let interm = init_fake_interm();
+        let words = |name: &str| interm.instructions[name].iter().map(|ins| ins.words()).collect::<Vec<u32>>();
+
+        assert_eq!(words("ldi"), vec![1]);
+        assert_eq!(words("out"), vec![1]);
+        assert_eq!(words("tst"), vec![1]);
+        assert_eq!(words("in"), vec![1]);
+        assert_eq!(words("breq"), vec![1]);
+        assert_eq!(words("call"), vec![2]);
+        assert_eq!(words("jmp"), vec![2]);
+        assert_eq!(words("lds"), vec![2, 1]);
     }
 
     #[test]
-    fn test_length() {
-        assert_eq!(length("ldi"), 16);
-        assert_eq!(length("out"), 16);
-        assert_eq!(length("tst"), 16);
-        assert_eq!(length("in"), 16);
-        assert_eq!(length("breq"), 16);
-        assert_eq!(length("call"), 32);
-        assert_eq!(length("jmp"), 32);
-        assert_eq!(length("lds"), 32);
+    fn test_parse() {
+        let interm = init_fake_interm();
+        let ins = |name: &str| interm.instructions[name][0];
+
+        assert_eq!(parse(ins("nop"), &[], 0), Ok(ObjectCode::Short(0x0000)));
+        assert_eq!(parse(ins("ldi"), &[Reg(16), Value(0xff)], 0), Ok(ObjectCode::Short(0xef0f)));
+        assert_eq!(parse(ins("ldi"), &[Reg(29), Value(0x8a)], 0), Ok(ObjectCode::Short(0xe8da)));
+        assert_eq!(parse(ins("add"), &[Reg(1), Reg(18)], 0), Ok(ObjectCode::Short(0x0e12)));
+        assert_eq!(parse(ins("clr"), &[Reg(17)], 0), Ok(ObjectCode::Short(0x2711)));
+        assert_eq!(parse(ins("dec"), &[Reg(19)], 0), Ok(ObjectCode::Short(0x953a)));
+        assert_eq!(parse(ins("out"), &[Value(0x05), Reg(16)], 0), Ok(ObjectCode::Short(0xb905)));
+        assert_eq!(parse(ins("in"), &[Reg(20), Value(0x3f)], 0), Ok(ObjectCode::Short(0xb74f)));
+        assert_eq!(parse(ins("sbi"), &[Value(0x04), Value(7)], 0), Ok(ObjectCode::Short(0x9a27)));
+        assert_eq!(parse(ins("adiw"), &[Reg(26), Value(0x21)], 0), Ok(ObjectCode::Short(0x9691)));
+        assert_eq!(parse(ins("movw"), &[Reg(30), Reg(24)], 0), Ok(ObjectCode::Short(0x01fc)));
+        assert_eq!(parse(ins("cbr"), &[Reg(16), Value(0x0f)], 0), Ok(ObjectCode::Short(0x7f00)));
+        assert_eq!(parse(ins("bset"), &[Value(7)], 0), Ok(ObjectCode::Short(0x9478)));
+        assert_eq!(parse(ins("brne"), &[Value(8)], 10), Ok(ObjectCode::Short(0xf7e9)));
+        assert_eq!(parse(ins("breq"), &[Value(12)], 10), Ok(ObjectCode::Short(0xf009)));
+        assert_eq!(parse(ins("rjmp"), &[Value(0)], 1), Ok(ObjectCode::Short(0xcffe)));
+        assert_eq!(parse(ins("rcall"), &[Value(0x105)], 5), Ok(ObjectCode::Short(0xd0ff)));
+        assert_eq!(parse(ins("brne"), &[Value(0)], 63), Ok(ObjectCode::Short(0xf601)));
+        assert_eq!(parse(ins("brne"), &[Value(64)], 0), Ok(ObjectCode::Short(0xf5f9)));
+        assert_eq!(parse(ins("brne"), &[Value(0)], 64),
+                   Err(String::from("target of \"brne\" out of range: distance is -65 words, limit is -64..+63 \
+                                     (use \"breq\" to skip over a \"rjmp\" or \"jmp\" to the target)")));
+        assert_eq!(parse(ins("brbs"), &[Value(1), Value(100)], 0),
+                   Err(String::from("target of \"brbs\" out of range: distance is 99 words, limit is -64..+63 \
+                                     (use \"brbc\" to skip over a \"rjmp\" or \"jmp\" to the target)")));
+        assert_eq!(parse(ins("rjmp"), &[Value(2048)], 0), Ok(ObjectCode::Short(0xc7ff)));
+        assert_eq!(parse(ins("rjmp"), &[Value(2049)], 0),
+                   Err(String::from("target of \"rjmp\" out of range: distance is 2048 words, limit is -2048..+2047")));
+        assert_eq!(parse(ins("rcall"), &[Value(0)], 2049),
+                   Err(String::from("target of \"rcall\" out of range: distance is -2050 words, limit is -2048..+2047")));
+        assert_eq!(parse(ins("jmp"), &[Value(0x23)], 0), Ok(ObjectCode::Long(0x940c_0023)));
+        assert_eq!(parse(ins("call"), &[Value(0x3_1234)], 0), Ok(ObjectCode::Long(0x941f_1234)));
+        assert_eq!(parse(ins("lds"), &[Reg(16), Value(0x10a)], 0), Ok(ObjectCode::Long(0x9100_010a)));
+        assert_eq!(parse(ins("sts"), &[Value(0x10b), Reg(16)], 0), Ok(ObjectCode::Long(0x9300_010b)));
+        assert_eq!(parse(ins("ldi"), &[Reg(16)], 0),
+                   Err(String::from("wrong number of operands for \"ldi\", found 1 (expected \"ldi Rd, K\")")));
+        assert_eq!(parse(ins("nop"), &[Reg(1)], 0),
+                   Err(String::from("wrong number of operands for \"nop\", found 1 (expected \"nop\")")));
+        assert_eq!(parse(ins("out"), &[Reg(16), Value(5)], 0),
+                   Err(String::from("operand 1 of \"out\" must be an I/O address, found register r16 \
+                                     (expected \"out A, Rr\")")));
+        assert_eq!(parse(ins("ldi"), &[Reg(16), Value(-1)], 0), Ok(ObjectCode::Short(0xef0f)));
+        assert_eq!(parse(ins("subi"), &[Reg(16), Value(-128)], 0), Ok(ObjectCode::Short(0x5800)));
+        assert_eq!(parse(ins("ldi"), &[Reg(16), Value(256)], 0),
+                   Err(String::from("operand 2 of \"ldi\" out of range (256), must be -128..255")));
+        assert_eq!(parse(ins("ldi"), &[Reg(16), Value(-129)], 0),
+                   Err(String::from("operand 2 of \"ldi\" out of range (-129), must be -128..255")));
+
+        // Constants are range checked before they are narrowed
+        let interm = init_fake_interm();
+        let operands = |line: &str| get_operands(String::from(line), &interm).unwrap();
+        assert_eq!(parse(ins("ldi"), &operands("ldi r16, 1<<32"), 0),
+                   Err(String::from("operand 2 of \"ldi\" out of range (4294967296), must be -128..255")));
+        assert_eq!(parse(ins("ldi"), &operands("ldi r17, 0xFFFFFFFF"), 0),
+                   Err(String::from("operand 2 of \"ldi\" out of range (4294967295), must be -128..255")));
+        assert_eq!(parse(ins("adiw"), &[Reg(24), Value(64)], 0),
+                   Err(String::from("operand 2 of \"adiw\" out of range (64), must be 0..63")));
+        assert_eq!(parse(ins("sbi"), &[Value(32), Value(0)], 0),
+                   Err(String::from("operand 1 of \"sbi\" out of range (32), must be 0..31")));
+        assert_eq!(parse(ins("bld"), &[Reg(0), Value(8)], 0),
+                   Err(String::from("operand 2 of \"bld\" out of range (8), must be 0..7")));
+        assert_eq!(parse(ins("out"), &[Value(0x40), Reg(0)], 0),
+                   Err(String::from("operand 1 of \"out\" out of range (64), must be 0..63")));
+        assert_eq!(parse(ins("lds"), &[Reg(0), Value(0x10000)], 0),
+                   Err(String::from("operand 2 of \"lds\" out of range (65536), must be 0..65535")));
+        assert_eq!(parse(ins("ldi"), &[Reg(15), Value(5)], 0),
+                   Err(String::from("operand 1 of \"ldi\" must be r16-r31, found r15")));
+        assert_eq!(parse(ins("ser"), &[Reg(0)], 0),
+                   Err(String::from("operand 1 of \"ser\" must be r16-r31, found r0")));
+        assert_eq!(parse(ins("muls"), &[Reg(16), Reg(2)], 0),
+                   Err(String::from("operand 2 of \"muls\" must be r16-r31, found r2")));
+        assert_eq!(parse(ins("fmul"), &[Reg(24), Reg(16)], 0),
+                   Err(String::from("operand 1 of \"fmul\" must be r16-r23, found r24")));
+        assert_eq!(parse(ins("mulsu"), &[Reg(23), Reg(16)], 0), Ok(ObjectCode::Short(0x0370)));
+        assert_eq!(parse(ins("movw"), &[Reg(3), Reg(0)], 0),
+                   Err(String::from("operand 1 of \"movw\" must be an even register, found r3")));
+        assert_eq!(parse(ins("adiw"), &[Reg(25), Value(1)], 0),
+                   Err(String::from("operand 1 of \"adiw\" must be r24, r26, r28 or r30, found r25")));
+        assert_eq!(parse(ins("sbiw"), &[Reg(30), Value(1)], 0), Ok(ObjectCode::Short(0x9731)));
+        assert_eq!(parse(ins("ldi"), &[Value(16), Value(5)], 0),
+                   Err(String::from("operand 1 of \"ldi\" must be a register, found value 16 (expected \"ldi Rd, K\")")));
+
+        let assemble = |line: &str| {
+            let (_, code, operands) = util::split_statement(line);
+            let ins = select(&interm, code.unwrap(), &operands).unwrap();
+            parse(ins, &get_operands(line.to_string(), &interm).unwrap(), 0)
+        };
+
+        assert_eq!(assemble("ld r16, X+"), Ok(ObjectCode::Short(0x910d)));
+        assert_eq!(assemble("ld r0, -Y"), Ok(ObjectCode::Short(0x900a)));
+        assert_eq!(assemble("st -Y, r0"), Ok(ObjectCode::Short(0x920a)));
+        assert_eq!(assemble("st Z, r31"), Ok(ObjectCode::Short(0x83f0)));
+        assert_eq!(assemble("ldd r24, Z+5"), Ok(ObjectCode::Short(0x8185)));
+        assert_eq!(assemble("std Y+63, r2"), Ok(ObjectCode::Short(0xae2f)));
+        assert_eq!(assemble("lpm r16, Z+"), Ok(ObjectCode::Short(0x9105)));
+        assert_eq!(assemble("lpm"), Ok(ObjectCode::Short(0x95c8)));
+        assert_eq!(assemble("elpm r0, Z"), Ok(ObjectCode::Short(0x9006)));
+    }
+
+    #[test]
+    fn test_layouts() {
+        for ins in INSTRUCTIONS {
+            let fields: String = ins.operands.iter().map(|kind| match *kind {
+                Rd(_) => "d",
+                Rr(_) => "r",
+                Rdr => "dr",
+                K8 | K8Inv | K6 => "K",
+                K16 | K7 | K7Rel | K12Rel | K22 => "k",
+                A5 | A6 => "A",
+                B => "b",
+                S => "s",
+                P(_) => "",
+                Pq(_) => "q",
+            }).collect();
+
+            assert!(ins.words() == 1 || ins.words() == 2, "{} has a bad length", ins.name);
+            assert_eq!(ins.bits().count() as u32, ins.words() * 16, "{}", ins.name);
+
+            for c in ins.bits() {
+                assert!(c == '0' || c == '1' || fields.contains(c), "{} has no operand for {}", ins.name, c);
+            }
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let interm = init_fake_interm();
+        let layout = |code: &str, operands: &[&str]| select(&interm, code, operands).map(|ins| ins.layout);
+
+        assert_eq!(layout("ld", &["r0", "X+"]), Ok("1001 000d dddd 1101"));
+        assert_eq!(layout("ld", &["r0", "-Y"]), Ok("1001 000d dddd 1010"));
+        assert_eq!(layout("ld", &["r0", "z"]), Ok("1000 000d dddd 0000"));
+        assert_eq!(layout("st", &["-Y", "r0"]), Ok("1001 001r rrrr 1010"));
+        assert_eq!(layout("st", &["X", "r0"]), Ok("1001 001r rrrr 1100"));
+        assert_eq!(layout("lpm", &[]), Ok("1001 0101 1100 1000"));
+        assert_eq!(layout("lpm", &["r16", "Z+"]), Ok("1001 000d dddd 0101"));
+        assert_eq!(layout("elpm", &["r0", "Z"]), Ok("1001 000d dddd 0110"));
+        assert_eq!(layout("elpm", &[]), Ok("1001 0101 1101 1000"));
+        assert_eq!(layout("ldi", &["r16", "0xff"]), Ok("1110 KKKK dddd KKKK"));
+        assert_eq!(layout("ldi", &["r16"]),
+                   Err(String::from("wrong number of operands for \"ldi\", found 1 (expected \"ldi Rd, K\")")));
+        assert_eq!(layout("nop", &["r1"]),
+                   Err(String::from("wrong number of operands for \"nop\", found 1 (expected \"nop\")")));
+        assert_eq!(layout("ldd", &["r0", "Y"]),
+                   Err(String::from("invalid operands for \"ldd\" (expected one of \"ldd Rd, Y+q\", \"ldd Rd, Z+q\")")));
+        assert_eq!(layout("lpm", &["r16", "X"]),
+                   Err(String::from("invalid operands for \"lpm\" (expected one of \"lpm\", \"lpm Rd, Z\", \"lpm Rd, Z+\")")));
+        assert_eq!(layout("ldx", &[]), Err(String::from("unknown instruction \"ldx\"")));
+        assert_eq!(layout("ldi", &["r16", "é+1"]), Ok("1110 KKKK dddd KKKK"));
+        assert_eq!(layout("ld", &["r0", "é+1"]),
+                   Err(String::from("invalid operands for \"ld\" (expected one of \"ld Rd, X\", \"ld Rd, X+\", \
+                                     \"ld Rd, -X\", \"ld Rd, Y\", \"ld Rd, Y+\", \"ld Rd, -Y\", \"ld Rd, Z\", \
+                                     \"ld Rd, Z+\", \"ld Rd, -Z\")")));
     }
 }
@@ -0,0 +1,340 @@
+//!
+//! The expr module evaluates the constant expressions used in
+//! operands and directives, e.g. `(1 << PB5) | (1 << PB4)`
+//!
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use util;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+///
+/// Binary operators from the lowest to the highest precedence,
+/// following the avrasm2 operator table
+///
+static BINARY: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+static OPERATORS: &[&str] = &[
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "&",
+    "^", "|", "!", "~",
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symtab: &'a HashMap<String, i64>,
+}
+
+///
+/// Evaluates an expression over the symbols in the SYMTAB
+///
+pub fn eval(text: &str, symtab: &HashMap<String, i64>) -> Result<i64, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        symtab,
+    };
+
+    if parser.tokens.is_empty() {
+        return Err(String::from("Missing expression"));
+    }
+
+    let value = parser.binary(0)?;
+
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(t) => Err(format!("Unexpected {} in expression", describe(t))),
+    }
+}
+
+///
+/// Splits an expression into numbers, symbols, operators
+/// and parentheses
+///
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' || c == '$' {
+            // Local labels of macro expansions end in ?n
+            let len = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '?')
+                .map_or(rest.len(), |n| n + 1);
+            let word = &rest[..len];
+
+            if c.is_ascii_digit() || c == '$' {
+                let value = util::num_from_str(word.to_string())?;
+                let value = i64::try_from(value).map_err(|_| format!("Number out of range ({})", word))?;
+                tokens.push(Token::Num(value));
+            } else {
+                tokens.push(Token::Ident(word.to_string()));
+            }
+
+            len
+        } else if c == '\'' {
+            let (value, len) = util::char_literal(rest)?;
+            tokens.push(Token::Num(i64::from(value)));
+            len
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            1
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            1
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                }
+                None => return Err(format!("Unexpected character '{}' in expression", c)),
+            }
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Num(n) => format!("number {}", n),
+        Token::Ident(ref s) => format!("symbol {}", s),
+        Token::Op(op) => format!("operator {}", op),
+        Token::Open => String::from("'('"),
+        Token::Close => String::from("')'"),
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    ///
+    /// Parses the binary operators of precedence `level` and
+    /// above, grouping from the left
+    ///
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == BINARY.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(&Token::Op(op)) if BINARY[level].contains(&op) => op,
+                _ => return Ok(lhs),
+            };
+
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = apply(op, lhs, rhs)?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Some(Token::Op("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(Token::Op("+")) => self.unary(),
+            Some(Token::Op("!")) => Ok((self.unary()? == 0) as i64),
+            Some(Token::Op("~")) => Ok(!self.unary()?),
+            Some(Token::Num(n)) => Ok(n),
+            Some(Token::Ident(name)) => {
+                if self.tokens.get(self.pos) == Some(&Token::Open) {
+                    let arg = self.unary()?;
+                    return call(&name, arg);
+                }
+
+                match self.symtab.get(&name) {
+                    Some(&n) => Ok(n),
+                    None => Err(format!("Undefined symbol {}", name)),
+                }
+            }
+            Some(Token::Open) => {
+                let value = self.binary(0)?;
+
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err(String::from("Missing ')' in expression")),
+                }
+            }
+            Some(t) => Err(format!("Unexpected {} in expression", describe(&t))),
+            None => Err(String::from("Unexpected end of expression")),
+        }
+    }
+}
+
+///
+/// Applies one of the built-in assembler functions
+///
+fn call(name: &str, arg: i64) -> Result<i64, String> {
+    Ok(match name.to_uppercase().as_str() {
+        "LOW" => arg & 0xff,
+        "HIGH" | "BYTE2" => (arg >> 8) & 0xff,
+        "BYTE3" => (arg >> 16) & 0xff,
+        "BYTE4" => (arg >> 24) & 0xff,
+        "LWRD" => arg & 0xffff,
+        "HWRD" => (arg >> 16) & 0xffff,
+        "PAGE" => (arg >> 16) & 0x3f,
+        "EXP2" if !(0..63).contains(&arg) => {
+            return Err(format!("Argument of EXP2 out of range ({})", arg));
+        }
+        "EXP2" => 1 << arg,
+        "LOG2" if arg <= 0 => return Err(format!("Argument of LOG2 must be positive ({})", arg)),
+        "LOG2" => 63 - i64::from(arg.leading_zeros()),
+        _ => return Err(format!("Unknown function {}", name)),
+    })
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    Ok(match op {
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" | ">>" if rhs < 0 => return Err(format!("Negative shift count ({})", rhs)),
+        // Shifting every bit out leaves zero, or the sign for >>
+        "<<" if rhs >= 64 => 0,
+        ">>" if rhs >= 64 => lhs >> 63,
+        "<<" => lhs << rhs,
+        ">>" => lhs >> rhs,
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err(String::from("Division by zero")),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn symtab() -> HashMap<String, i64> {
+        let mut symtab = HashMap::new();
+        symtab.insert(String::from("PB4"), 4);
+        symtab.insert(String::from("PB5"), 5);
+        symtab.insert(String::from("PORTB"), 0x05);
+        symtab.insert(String::from("RAMEND"), 0x21ff);
+        symtab.insert(String::from("table"), 0x1234);
+        symtab
+    }
+
+    #[test]
+    fn test_eval() {
+        let symtab = symtab();
+        let eval = |s: &str| eval(s, &symtab);
+
+        assert_eq!(eval("10"), Ok(10));
+        assert_eq!(eval("0xFF"), Ok(0xff));
+        assert_eq!(eval("(1<<PB5)|(1<<PB4)"), Ok(0x30));
+        assert_eq!(eval("(1 << PB5) | (1 << PB4)"), Ok(0x30));
+        assert_eq!(eval("PORTB+1"), Ok(6));
+        assert_eq!(eval("1 << 64"), Ok(0));
+        assert_eq!(eval("0x100 >> 64"), Ok(0));
+        assert_eq!(eval("-8 >> 70"), Ok(-1));
+        assert_eq!(eval("1 << 63"), Ok(i64::MIN));
+        assert_eq!(eval("RAMEND-1"), Ok(0x21fe));
+        assert_eq!(eval("2 + 3 * 4"), Ok(14));
+        assert_eq!(eval("(2 + 3) * 4"), Ok(20));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("7 / 2 % 2"), Ok(1));
+        assert_eq!(eval("-5 + 2"), Ok(-3));
+        assert_eq!(eval("~0x0f & 0xff"), Ok(0xf0));
+        assert_eq!(eval("!0"), Ok(1));
+        assert_eq!(eval("!PB4"), Ok(0));
+        assert_eq!(eval("3 > 2 && 2 >= 2"), Ok(1));
+        assert_eq!(eval("3 < 2 || 1 != 1"), Ok(0));
+        assert_eq!(eval("1 | 2 ^ 3 & 6"), Ok(1));
+        assert_eq!(eval("PB4 == 4"), Ok(1));
+        assert_eq!(eval("0x80 >> 3"), Ok(0x10));
+        assert_eq!(eval("$FF"), Ok(0xff));
+        assert_eq!(eval("$10+$01"), Ok(0x11));
+        assert_eq!(eval("010"), Ok(8));
+        assert_eq!(eval("'A'"), Ok(65));
+        assert_eq!(eval("'a' - 'A'"), Ok(32));
+        assert_eq!(eval("'\\n'"), Ok(10));
+        assert_eq!(eval("0b1111_0000 | 0x0F"), Ok(0xff));
+        assert_eq!(eval("-1"), Ok(-1));
+    }
+
+    #[test]
+    fn test_functions() {
+        let symtab = symtab();
+        let eval = |s: &str| eval(s, &symtab);
+
+        assert_eq!(eval("LOW(2*table)"), Ok(0x68));
+        assert_eq!(eval("HIGH(2*table)"), Ok(0x24));
+        assert_eq!(eval("low(RAMEND)"), Ok(0xff));
+        assert_eq!(eval("high(RAMEND)"), Ok(0x21));
+        assert_eq!(eval("BYTE2(0x12345678)"), Ok(0x56));
+        assert_eq!(eval("BYTE3(0x12345678)"), Ok(0x34));
+        assert_eq!(eval("BYTE4(0x12345678)"), Ok(0x12));
+        assert_eq!(eval("LWRD(0x12345678)"), Ok(0x5678));
+        assert_eq!(eval("HWRD(0x12345678)"), Ok(0x1234));
+        assert_eq!(eval("PAGE(0x3f1234)"), Ok(0x3f));
+        assert_eq!(eval("EXP2(PB5)"), Ok(32));
+        assert_eq!(eval("LOG2(1024)"), Ok(10));
+        assert_eq!(eval("LOG2(1023)"), Ok(9));
+        assert_eq!(eval("HIGH(table) + LOW(table) * 2"), Ok(0x12 + 0x68));
+        assert_eq!(eval("-LOW(1)"), Ok(-1));
+        assert_eq!(eval("LOG2(0)"), Err(String::from("Argument of LOG2 must be positive (0)")));
+        assert_eq!(eval("EXP2(-1)"), Err(String::from("Argument of EXP2 out of range (-1)")));
+        assert_eq!(eval("FOO(1)"), Err(String::from("Unknown function FOO")));
+        assert_eq!(eval("LOW 1"), Err(String::from("Undefined symbol LOW")));
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let symtab = symtab();
+        let eval = |s: &str| eval(s, &symtab);
+
+        assert_eq!(eval("done"), Err(String::from("Undefined symbol done")));
+        assert_eq!(eval(""), Err(String::from("Missing expression")));
+        assert_eq!(eval("0xFFFFFFFFFFFFFFFF"), Err(String::from("Number out of range (0xFFFFFFFFFFFFFFFF)")));
+        assert_eq!(eval("(1 + 2"), Err(String::from("Missing ')' in expression")));
+        assert_eq!(eval("1 +"), Err(String::from("Unexpected end of expression")));
+        assert_eq!(eval("1 2"), Err(String::from("Unexpected number 2 in expression")));
+        assert_eq!(eval("4 / 0"), Err(String::from("Division by zero")));
+        assert_eq!(eval("1 << -1"), Err(String::from("Negative shift count (-1)")));
+        assert_eq!(eval("0x100 >> -64"), Err(String::from("Negative shift count (-64)")));
+        assert_eq!(eval("1 @ 2"), Err(String::from("Unexpected character '@' in expression")));
+        assert_eq!(eval("$"), Err(String::from("cannot parse integer from empty string")));
+        assert_eq!(eval("'AB'"), Err(String::from("Unterminated character literal")));
+    }
+}
@@ -3,20 +3,23 @@ extern crate hashbrown;
 #[macro_use]
 extern crate derivative;
 
-use std::collections::HashMap;
 use std::env;
-use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod assembler;
+mod expr;
 mod util;
 mod preproc;
 
 pub struct Args {
     bin: bool,
     verbose: bool,
+    relax: bool,
+    include: Vec<String>,
+    device: Option<String>,
+    listing: Option<String>,
     path: Option<String>,
 }
 
@@ -38,46 +41,74 @@ fn main() {
     let mut args = Args {
         bin: false,
         verbose: false,
+        relax: false,
+        include: Vec::new(),
+        device: None,
+        listing: None,
         path: None,
     };
 
-    for arg in cmd_args {
+    let mut cmd_args = cmd_args.into_iter().skip(1);
+
+    while let Some(arg) = cmd_args.next() {
         match arg.as_str() {
             "--bin" => args.bin = true,
             "--verbose" => args.verbose = true,
+            "--relax" => args.relax = true,
+            "-I" => match cmd_args.next() {
+                Some(dir) => args.include.push(dir),
+                None => {
+                    fail!("No directory specified for -I");
+                }
+            },
+            "--device" => match cmd_args.next() {
+                Some(name) => args.device = Some(name),
+                None => {
+                    fail!("No device specified for --device");
+                }
+            },
+            "-l" => match cmd_args.next() {
+                Some(path) => args.listing = Some(path),
+                None => {
+                    fail!("No listing file specified for -l");
+                }
+            },
+            _ if arg.starts_with("-I") => args.include.push(arg[2..].to_string()),
             _ => args.path = Some(arg),
         }
     }
 
-    let path = match args.path {
-        Some(s) => s,
-        None => String::new(),
-    };
+    let path = args.path.unwrap_or_default();
     let path = Path::new(&path);
 
-    let mut file = match File::open(&path) {
+    let mut file = match File::open(path) {
         Err(why) => {
-            fail!(format!("Failed to open file: {}", why.description()));
+            fail!(format!("Failed to open file: {}", why));
         }
         Ok(file) => file,
     };
 
     let mut s = String::new();
-    match file.read_to_string(&mut s) {
-        Err(why) => {
-            fail!(format!("Failed to open file: {}", why.description()));
+    if let Err(why) = file.read_to_string(&mut s) {
+        fail!(format!("Failed to open file: {}", why));
+    }
+
+    let mut interm = assembler::Interm::new();
+    interm.path = Some(path.to_path_buf());
+    interm.include_dirs = args.include.iter().map(PathBuf::from).collect();
+
+    if let Some(name) = args.device {
+        match assembler::device::find(&name) {
+            Some(device) => interm.set_device(device),
+            None => {
+                fail!(format!("Unknown device \"{}\"", name));
+            }
         }
-        Ok(_) => {}
     }
 
-    let mut interm = assembler::Interm {
-        lines: Vec::new(),
-        optab: Vec::new(),
-        instructions: HashMap::new(),
-        locctr: 0,
-        linectr: 0,
-        symtab: HashMap::new(),
-    };
+    if args.listing.is_some() {
+        interm.listing = Some(assembler::listing::Listing::new());
+    }
 
     let result = preproc::parse(&s, &mut interm);
 
@@ -88,27 +119,42 @@ fn main() {
         }
     }
 
-    let result = assembler::first_pass(&s, &mut interm);
+    let result = if args.relax {
+        assembler::relax(&s, &mut interm)
+    } else {
+        assembler::first_pass(&s, &mut interm)
+    };
 
-    match result {
-        Ok(_) => {
-            println!("{:?}", interm);
-        }
-        Err(e) => {
-            fail!(e);
-        }
+    for diagnostic in &interm.diagnostics {
+        eprintln!("{}\n", diagnostic);
     }
 
-    println!("---             ---");
-    println!("--- Second pass ---");
-    println!("---             ---");
+    if let Err(e) = result {
+        fail!(e);
+    }
 
-    let result = assembler::second_pass(&s, &mut interm);
+    if args.verbose {
+        println!("--- First pass ---");
+        println!("{:?}", interm);
+    }
 
-    match result {
-        Ok(_) => println!("{:?}", interm),
-        Err(e) => {
-            fail!(e);
+    if let Err(e) = assembler::second_pass(&mut interm) {
+        fail!(e);
+    }
+
+    if args.verbose {
+        println!("--- Second pass ---");
+        println!("{:?}", interm);
+    }
+
+    if let Some(path) = args.listing {
+        if let Err(why) = fs::write(&path, assembler::listing::generate(&interm)) {
+            fail!(format!("Failed to write listing file: {}", why));
         }
     }
+
+    match interm.sram_size {
+        Some(size) => println!("RAM used: {} of {} bytes", interm.ram_used(), size),
+        None => println!("RAM used: {} bytes", interm.ram_used()),
+    }
 }
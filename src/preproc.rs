@@ -3,16 +3,17 @@
 //!
 
 use assembler::Interm;
+use expr;
 use util;
 
 ///
 /// The parse function processes preprocessor macros like #define,
 /// #undef, etc
 ///
-pub fn parse(file: &String, interm: &mut Interm) -> Result<(), String> {
+pub fn parse(file: &str, interm: &mut Interm) -> Result<(), String> {
     for line in file.lines() {
         let line = line.to_string();
-        let words = util::split_string(&line);
+        let words = util::split_string(util::strip_comment(&line));
 
         interm.linectr += 1;
 
@@ -24,7 +25,13 @@ pub fn parse(file: &String, interm: &mut Interm) -> Result<(), String> {
                     symbol, interm.linectr, line
                 ));
             } else if words.len() > 2 {
-                match words[2].parse::<u32>() {
+                // The words are separated by commas as well as spaces,
+                // so look for where each one actually is
+                let text = util::strip_comment(&line);
+                let text = &text[text.find(words[0]).unwrap_or(0) + words[0].len()..];
+                let value = &text[text.find(symbol).unwrap_or(0) + symbol.len()..];
+
+                match expr::eval(value, &interm.symtab) {
                     Ok(n) => {
                         interm.symtab.insert(symbol.to_string(), n);
                     }
@@ -43,3 +50,17 @@ pub fn parse(file: &String, interm: &mut Interm) -> Result<(), String> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_define() {
+        let mut interm = Interm::new();
+        parse("#DEFINE SIZE 4 * 2\n#DEFINE ,é 1\n#DEFINE FLAG", &mut interm).unwrap();
+        assert_eq!(interm.symtab["SIZE"], 8);
+        assert_eq!(interm.symtab["é"], 1);
+        assert_eq!(interm.symtab["FLAG"], 0);
+    }
+}
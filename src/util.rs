@@ -1,24 +1,118 @@
-use std::u32;
-
-///
-/// Takes in a number in either base 2, 10 or 16 and returns
-/// the binary value.
-///
-pub fn num_from_str(string: String) -> Result<u32, String> {
-    match &string[..2] {
-        "0x" => match u32::from_str_radix(&string[2..], 16) {
-            Ok(n) => return Ok(n),
-            Err(e) => return Err(e.to_string()),
-        },
-        "0b" => match u32::from_str_radix(&string[2..], 2) {
-            Ok(n) => return Ok(n),
-            Err(e) => return Err(e.to_string()),
-        },
-        _ => match string.parse::<u32>() {
-            Ok(n) => return Ok(n),
-            Err(e) => return Err(e.to_string()),
-        },
+///
+/// Takes in a number in base 2, 8, 10 or 16 and returns
+/// the binary value. Hexadecimal numbers start with `0x` or
+/// `$`, binary numbers with `0b` and octal numbers with a
+/// leading zero. Underscores can be used to group digits.
+///
+pub fn num_from_str(string: String) -> Result<u64, String> {
+    let string = string.replace('_', "");
+
+    let result = match string.get(..2) {
+        Some("0x") | Some("0X") => u64::from_str_radix(&string[2..], 16),
+        Some("0b") | Some("0B") => u64::from_str_radix(&string[2..], 2),
+        _ if string.starts_with('$') => u64::from_str_radix(&string[1..], 16),
+        _ if string.len() > 1 && string.starts_with('0') => u64::from_str_radix(&string[1..], 8),
+        _ => string.parse::<u64>(),
+    };
+
+    result.map_err(|e| e.to_string())
+}
+
+///
+/// Reads one character from the start of `text`, translating
+/// backslash escapes. Returns the character value and the
+/// number of bytes read.
+///
+pub fn read_char(text: &str) -> Result<(u32, usize), String> {
+    let mut chars = text.chars();
+
+    match chars.next() {
+        Some('\\') => {}
+        Some(c) => return Ok((c as u32, c.len_utf8())),
+        None => return Err(String::from("Missing character")),
+    }
+
+    let value = match chars.next() {
+        Some('n') => '\n' as u32,
+        Some('r') => '\r' as u32,
+        Some('t') => '\t' as u32,
+        Some('0') => 0,
+        Some('\\') => '\\' as u32,
+        Some('\'') => '\'' as u32,
+        Some('"') => '"' as u32,
+        Some('x') => {
+            let hex: String = chars.take(2).collect();
+            return match u32::from_str_radix(&hex, 16) {
+                Ok(n) if hex.len() == 2 => Ok((n, 4)),
+                _ => Err(format!("Invalid escape sequence \\x{}", hex)),
+            };
+        }
+        Some(c) => return Err(format!("Invalid escape sequence \\{}", c)),
+        None => return Err(String::from("Missing character")),
+    };
+
+    Ok((value, 2))
+}
+
+///
+/// Parses a character literal such as `'A'` or `'\n'` from the
+/// start of `text`. Returns the character value and the
+/// length of the literal in bytes.
+///
+pub fn char_literal(text: &str) -> Result<(u32, usize), String> {
+    if !text.starts_with('\'') {
+        return Err(String::from("Missing character literal"));
+    }
+
+    let (value, len) = read_char(&text[1..])?;
+
+    if text[1 + len..].starts_with('\'') {
+        Ok((value, len + 2))
+    } else {
+        Err(String::from("Unterminated character literal"))
+    }
+}
+
+///
+/// Reads a double quoted string such as "Hello\n" and returns
+/// the value of each character. Anything after the closing
+/// quote is an error.
+///
+pub fn string_literal(text: &str) -> Result<Vec<u32>, String> {
+    if !text.starts_with('"') {
+        return Err(String::from("Missing string literal"));
     }
+
+    let mut ret = Vec::new();
+    let mut i = 1;
+
+    loop {
+        if text[i..].starts_with('"') {
+            break;
+        }
+
+        if i >= text.len() {
+            return Err(String::from("Unterminated string literal"));
+        }
+
+        let (value, len) = read_char(&text[i..])?;
+        ret.push(value);
+        i += len;
+    }
+
+    if !text[i + 1..].trim().is_empty() {
+        return Err(format!("Unexpected text after string literal: {}", text[i + 1..].trim()));
+    }
+
+    Ok(ret)
+}
+
+///
+/// Checks whether `text` can name a symbol, that is whether it
+/// is made of letters, digits and underscores
+///
+pub fn is_name(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
 ///
@@ -26,10 +120,100 @@ pub fn num_from_str(string: String) -> Result<u32, String> {
 /// the split characters. Also removes empty strings
 /// from the resulting split vector
 ///
-pub fn split_string<'a>(line: &'a String) -> Vec<&'a str> {
+pub fn split_string(line: &str) -> Vec<&str> {
     line.split(|c: char| c == ',' || c.is_whitespace()).filter(|i| i != &"").collect()
 }
 
+///
+/// Returns the part of a line before its comment. Semicolons
+/// inside quotes do not start a comment, and a quote escaped
+/// with a backslash does not end the quoted text.
+///
+pub fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            _ if escaped => escaped = false,
+            ('\\', Some(_)) => escaped = true,
+            (';', None) => return &line[..i],
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+    }
+
+    line
+}
+
+///
+/// Splits an operand list on the commas that are not inside
+/// parentheses or quotes. Operands are trimmed and an empty
+/// list yields no operands.
+///
+pub fn split_operands(text: &str) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        match (c, quote) {
+            _ if escaped => escaped = false,
+            ('\\', Some(_)) => escaped = true,
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('(', None) => depth += 1,
+            (')', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                ret.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    let last = text[start..].trim();
+    if !last.is_empty() || !ret.is_empty() {
+        ret.push(last);
+    }
+
+    ret
+}
+
+///
+/// Splits the label off the front of a statement. The label
+/// is returned without its colon and the rest is trimmed.
+///
+pub fn split_label(line: &str) -> (Option<&str>, &str) {
+    let rest = line.trim();
+    let first = rest.split_whitespace().next().unwrap_or("");
+
+    match first.find(':') {
+        Some(colon) => (Some(&first[..colon]), rest[colon + 1..].trim_start()),
+        None => (None, rest),
+    }
+}
+
+///
+/// Splits a line into its label, mnemonic and operands. The
+/// comment is removed and the label is returned without its
+/// colon.
+///
+pub fn split_statement(line: &str) -> (Option<&str>, Option<&str>, Vec<&str>) {
+    let (label, rest) = split_label(strip_comment(line));
+
+    if rest.is_empty() {
+        return (label, None, Vec::new());
+    }
+
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    (label, Some(&rest[..end]), split_operands(&rest[end..]))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -37,8 +221,21 @@ mod test {
     #[test]
     fn test_num_from_str() {
         assert_eq!(num_from_str(String::from("10")), Ok(10));
+        assert_eq!(num_from_str(String::from("5")), Ok(5));
+        assert_eq!(num_from_str(String::from("0")), Ok(0));
+        assert_eq!(num_from_str(String::from("$FF")), Ok(0xFF));
+        assert_eq!(num_from_str(String::from("$1a2B")), Ok(0x1a2b));
+        assert_eq!(num_from_str(String::from("0X1F")), Ok(0x1F));
+        assert_eq!(num_from_str(String::from("017")), Ok(0o17));
+        assert_eq!(num_from_str(String::from("1_000_000")), Ok(1000000));
+        assert_eq!(num_from_str(String::from("0b1010_0101")), Ok(0xa5));
+        assert_eq!(num_from_str(String::from("0xFF_FF")), Ok(0xffff));
+        assert_eq!(num_from_str(String::from("09")), Err(String::from("invalid digit found in string")));
         assert_eq!(num_from_str(String::from("0xFF")), Ok(0xFF));
         assert_eq!(num_from_str(String::from("0b1000")), Ok(0b1000));
+        assert_eq!(num_from_str(String::from("0x1122334455667788")), Ok(0x1122_3344_5566_7788));
+        assert_eq!(num_from_str(String::from("0x1_0000_0000_0000_0000")),
+                   Err(String::from("number too large to fit in target type")));
         assert_eq!(num_from_str(String::from("wuadbu")), Err(String::from("invalid digit found in string")));
         assert_eq!(num_from_str(String::from("0xTT")), Err(String::from("invalid digit found in string")));
         assert_eq!(num_from_str(String::from("0bJJJ")), Err(String::from("invalid digit found in string")));
@@ -62,4 +259,72 @@ mod test {
                 &String::from("testing just spaces here")),
                 vec!["testing", "just", "spaces", "here"]);
     }
+
+    #[test]
+    fn test_is_name() {
+        assert!(is_name("PORTB"));
+        assert!(is_name("_loop2"));
+        assert!(!is_name(""));
+        assert!(!is_name("loop?1"));
+        assert!(!is_name("a-b"));
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("ldi r16, 9 ;this is a comment"), "ldi r16, 9 ");
+        assert_eq!(strip_comment("out DDRB, r16;comment"), "out DDRB, r16");
+        assert_eq!(strip_comment("; only a comment"), "");
+        assert_eq!(strip_comment(".db \"a;b\", ';' ; c"), ".db \"a;b\", ';' ");
+        assert_eq!(strip_comment("nop"), "nop");
+        assert_eq!(strip_comment(".db \"a\\\";b\", 0 ; c"), ".db \"a\\\";b\", 0 ");
+        assert_eq!(strip_comment(".db '\\'', ';' ; c"), ".db '\\'', ';' ");
+    }
+
+    #[test]
+    fn test_split_operands() {
+        assert_eq!(split_operands(" r16, 0xff "), vec!["r16", "0xff"]);
+        assert_eq!(split_operands("r16, (1 << PB5) | (1 << PB4)"), vec!["r16", "(1 << PB5) | (1 << PB4)"]);
+        assert_eq!(split_operands("PORTB ,r16"), vec!["PORTB", "r16"]);
+        assert_eq!(split_operands("\"a, b\", ','"), vec!["\"a, b\"", "','"]);
+        assert_eq!(split_operands("   "), Vec::<&str>::new());
+        assert_eq!(split_operands("1,,2"), vec!["1", "", "2"]);
+        assert_eq!(split_operands("\"a\\\"b\", 0"), vec!["\"a\\\"b\"", "0"]);
+        assert_eq!(split_operands("\"x\\\", y\", 0"), vec!["\"x\\\", y\"", "0"]);
+        assert_eq!(split_operands("'\\\\', ','"), vec!["'\\\\'", "','"]);
+    }
+
+    #[test]
+    fn test_split_statement() {
+        assert_eq!(split_statement("test: ldi r16, 9 ;this is a comment"),
+                   (Some("test"), Some("ldi"), vec!["r16", "9"]));
+        assert_eq!(split_statement("\tout PORTB ,r16"), (None, Some("out"), vec!["PORTB", "r16"]));
+        assert_eq!(split_statement("done:"), (Some("done"), None, vec![]));
+        assert_eq!(split_statement("done:jmp done"), (Some("done"), Some("jmp"), vec!["done"]));
+        assert_eq!(split_statement("  nop"), (None, Some("nop"), vec![]));
+        assert_eq!(split_statement("; comment"), (None, None, vec![]));
+    }
+
+    #[test]
+    fn test_char_literal() {
+        assert_eq!(char_literal("'A'"), Ok((65, 3)));
+        assert_eq!(char_literal("'A' + 1"), Ok((65, 3)));
+        assert_eq!(char_literal("'\\n'"), Ok((10, 4)));
+        assert_eq!(char_literal("'\\''"), Ok((39, 4)));
+        assert_eq!(char_literal("'\\0'"), Ok((0, 4)));
+        assert_eq!(char_literal("'\\x41'"), Ok((0x41, 6)));
+        assert_eq!(char_literal("';'"), Ok((59, 3)));
+        assert_eq!(char_literal("'AB'"), Err(String::from("Unterminated character literal")));
+        assert_eq!(char_literal("'\\q'"), Err(String::from("Invalid escape sequence \\q")));
+        assert_eq!(char_literal("'"), Err(String::from("Missing character")));
+    }
+
+    #[test]
+    fn test_string_literal() {
+        assert_eq!(string_literal("\"AB\""), Ok(vec![65, 66]));
+        assert_eq!(string_literal("\"\""), Ok(vec![]));
+        assert_eq!(string_literal("\"a;b,c\""), Ok(vec![97, 59, 98, 44, 99]));
+        assert_eq!(string_literal("\"\\\"\\n\\x41\""), Ok(vec![34, 10, 0x41]));
+        assert_eq!(string_literal("\"AB"), Err(String::from("Unterminated string literal")));
+        assert_eq!(string_literal("\"AB\" 1"), Err(String::from("Unexpected text after string literal: 1")));
+    }
 }